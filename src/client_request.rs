@@ -1,15 +1,27 @@
 //! All of the code needed for sending client requests to a SQRL server
 
+#[cfg(feature = "batch")]
+use crate::is_canonical_scalar;
 use crate::{
-    decode_public_key, decode_signature, encode_newline_data,
+    check_base64_field, decode_base64_payload, decode_public_key, decode_signature,
+    decode_signature_strict, encode_newline_data, encode_public_key, encode_signature,
     error::SqrlError,
-    get_or_error, parse_newline_data, parse_query_data,
-    server_response::{ServerResponse, TIFValue},
-    ProtocolVersion, Result, SqrlUrl, PROTOCOL_VERSIONS,
+    get_base64_field, get_or_error, parse_form_data, parse_newline_data, parse_query_data,
+    server_response::{ServerResponse, TIFValue, QUERY_URL_KEY, TIF_KEY},
+    Nut, ProtocolVersion, Result, SecretString, SqrlUrl, KEY_LENGTH, NUT_KEY, PROTOCOL_VERSIONS,
+};
+use alloc::{
+    borrow::ToOwned,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
 };
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
-use ed25519_dalek::{Signature, VerifyingKey};
-use std::{collections::HashMap, convert::TryFrom, fmt, result, str::FromStr};
+use core::{fmt, result, str::FromStr};
+#[cfg(feature = "batch")]
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 
 // Keys used for encoding ClientRequest
 const CLIENT_PARAMETERS_KEY: &str = "client";
@@ -30,7 +42,17 @@ const PREVIOUS_INDEX_SECRET_KEY: &str = "pins";
 const SERVER_UNLOCK_KEY_KEY: &str = "suk";
 const VERIFY_UNLOCK_KEY_KEY: &str = "vuk";
 
+// The highest button number a server can offer in an ask
+const MAX_BUTTON: u8 = 2;
+
 /// A client request to a server
+///
+/// The struct is `#[non_exhaustive]` so a parsed request can keep the client
+/// parameters exactly as received. Create requests with
+/// [`ClientRequest::new`], [`ClientRequestBuilder`], or by parsing them
+/// rather than with a struct literal.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct ClientRequest {
     /// The client parameters
     pub client_params: ClientParameters,
@@ -42,6 +64,21 @@ pub struct ClientRequest {
     pub previous_identity_signature: Option<Signature>,
     /// The unlock request signature for an identity unlock (urs)
     pub unlock_request_signature: Option<String>,
+    // The client parameters exactly as received, with this crate's encoding
+    // of what they decoded to, so an unmodified parsed request can be signed
+    // and re-encoded byte for byte. The server data keeps its own received
+    // value.
+    received_client_params: Option<(SecretString, SecretString)>,
+}
+
+impl PartialEq for ClientRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.client_params == other.client_params
+            && self.server_data == other.server_data
+            && self.identity_signature == other.identity_signature
+            && self.previous_identity_signature == other.previous_identity_signature
+            && self.unlock_request_signature == other.unlock_request_signature
+    }
 }
 
 impl ClientRequest {
@@ -57,18 +94,243 @@ impl ClientRequest {
             identity_signature,
             previous_identity_signature: None,
             unlock_request_signature: None,
+            received_client_params: None,
+        }
+    }
+
+    /// Get the command requested by the client
+    /// ```rust
+    /// use ed25519_dalek::SigningKey;
+    /// use sqrl_protocol::{
+    ///     client_request::{ClientCommand, ClientRequestBuilder, ServerData},
+    ///     SqrlUrl,
+    /// };
+    ///
+    /// let url = SqrlUrl::parse("sqrl://example.com?nut=1234abcd").unwrap();
+    /// let request = ClientRequestBuilder::new(ClientCommand::Query, ServerData::from(url))
+    ///     .build_and_sign(&SigningKey::from_bytes(&[1; 32]))
+    ///     .unwrap();
+    ///
+    /// match request.command() {
+    ///     ClientCommand::Query => println!("Look up {:?}", request.identity_key()),
+    ///     _ => panic!("Expected a query"),
+    /// }
+    /// ```
+    pub fn command(&self) -> &ClientCommand {
+        &self.client_params.command
+    }
+
+    /// Get the identity key (idk) used to sign the request
+    pub fn identity_key(&self) -> &VerifyingKey {
+        &self.client_params.identity_key
+    }
+
+    /// Get the raw bytes of the identity signature (ids)
+    pub fn identity_signature_bytes(&self) -> [u8; 64] {
+        self.identity_signature.to_bytes()
+    }
+
+    /// Get the nut of the server data the client is responding to
+    pub fn server_nut(&self) -> Option<Nut> {
+        self.server_data.nut()
+    }
+
+    /// Get the auth domain of the SQRL url the client signed, so a server
+    /// can check the request was made for its own domain. Only the first
+    /// request in an exchange echoes the url, so later requests return `None`.
+    /// ```rust
+    /// use ed25519_dalek::SigningKey;
+    /// use sqrl_protocol::{
+    ///     client_request::{ClientCommand, ClientRequestBuilder, ServerData},
+    ///     SqrlUrl,
+    /// };
+    ///
+    /// let url = SqrlUrl::parse("sqrl://example.com/sqrl?nut=1234abcd").unwrap();
+    /// let request = ClientRequestBuilder::new(ClientCommand::Query, ServerData::from(url))
+    ///     .build_and_sign(&SigningKey::from_bytes(&[1; 32]))
+    ///     .unwrap();
+    /// assert_eq!(Some("example.com/sqrl".to_owned()), request.auth_domain());
+    /// ```
+    pub fn auth_domain(&self) -> Option<String> {
+        match &self.server_data {
+            ServerData::Url { url } => Some(url.get_auth_domain()),
+            ServerData::ServerResponse { .. } => None,
+        }
+    }
+
+    /// Create and sign the first query of an exchange, sent in response to
+    /// the SQRL url a server advertised
+    /// ```rust
+    /// use ed25519_dalek::SigningKey;
+    /// use sqrl_protocol::{client_request::ClientRequest, SqrlUrl};
+    ///
+    /// let url = SqrlUrl::parse("sqrl://example.com/cli.sqrl?nut=1234abcd").unwrap();
+    /// let request = ClientRequest::new_query(&url, &SigningKey::from_bytes(&[1; 32]));
+    /// request.verify_and_validate().unwrap();
+    /// let body = request.to_query_string();
+    /// ```
+    pub fn new_query(url: &SqrlUrl, identity_key: &SigningKey) -> Self {
+        let client_params =
+            ClientParameters::new(ClientCommand::Query, identity_key.verifying_key());
+        let mut request = ClientRequest::new(
+            client_params,
+            ServerData::from(url.clone()),
+            Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+        );
+        request.identity_signature = identity_key.sign(request.signed_bytes().as_bytes());
+
+        request
+    }
+
+    /// Sign a request with both the identity key (ids) and the unlock request
+    /// key (urs), as needed by the enable and remove commands
+    ///
+    /// The parameters' identity key (idk) must belong to `identity_key`. No
+    /// previous identity signature is made, so the parameters can't set a
+    /// previous identity key (pidk).
+    pub fn sign_with_unlock(
+        client_params: ClientParameters,
+        server_data: ServerData,
+        identity_key: &SigningKey,
+        unlock_key: &SigningKey,
+    ) -> Result<Self> {
+        if client_params.identity_key != identity_key.verifying_key() {
+            return Err(SqrlError::new(
+                "Identity key (idk) does not match the key signing the request".to_owned(),
+            ));
+        }
+        if client_params.previous_identity_key.is_some() {
+            return Err(SqrlError::new(
+                "Previous identity key (pidk) set without a previous identity to sign with"
+                    .to_owned(),
+            ));
+        }
+
+        let mut request = ClientRequest::new(
+            client_params,
+            server_data,
+            Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+        );
+        let signed_string = request.signed_bytes();
+        request.identity_signature = identity_key.sign(signed_string.as_bytes());
+        request.unlock_request_signature =
+            Some(encode_signature(&unlock_key.sign(signed_string.as_bytes())));
+
+        Ok(request)
+    }
+
+    /// Sign a request with both the identity key (ids) and a previous
+    /// identity (pids), as sent when a client's identity has been rekeyed
+    ///
+    /// The previous identity key (pidk) is set from `previous_identity`, so
+    /// the pidk and pids always come as a pair. The parameters' identity key
+    /// (idk) must belong to `identity_key`.
+    /// ```rust
+    /// use ed25519_dalek::SigningKey;
+    /// use sqrl_protocol::{
+    ///     client_request::{ClientCommand, ClientParameters, ClientRequest, ServerData},
+    ///     SqrlUrl,
+    /// };
+    ///
+    /// let identity = SigningKey::from_bytes(&[1; 32]);
+    /// let previous = SigningKey::from_bytes(&[2; 32]);
+    /// let url = SqrlUrl::parse("sqrl://example.com/cli.sqrl?nut=1234abcd").unwrap();
+    /// let request = ClientRequest::sign_with_previous_identity(
+    ///     ClientParameters::new(ClientCommand::Query, identity.verifying_key()),
+    ///     ServerData::from(url),
+    ///     &identity,
+    ///     &previous,
+    /// )
+    /// .unwrap();
+    /// request.verify_and_validate().unwrap();
+    /// ```
+    pub fn sign_with_previous_identity(
+        mut client_params: ClientParameters,
+        server_data: ServerData,
+        identity_key: &SigningKey,
+        previous_identity: &SigningKey,
+    ) -> Result<Self> {
+        if client_params.identity_key != identity_key.verifying_key() {
+            return Err(SqrlError::new(
+                "Identity key (idk) does not match the key signing the request".to_owned(),
+            ));
         }
+        client_params.previous_identity_key = Some(previous_identity.verifying_key());
+
+        let mut request = ClientRequest::new(
+            client_params,
+            server_data,
+            Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+        );
+        let signed_string = request.signed_bytes();
+        request.identity_signature = identity_key.sign(signed_string.as_bytes());
+        request.previous_identity_signature =
+            Some(previous_identity.sign(signed_string.as_bytes()));
+
+        Ok(request)
+    }
+
+    /// Create a copy of this request to resend after a transient error,
+    /// using the server data from the new response and signing it again
+    ///
+    /// The previous identity (pids) and unlock request (urs) signatures were
+    /// made over the old server data, so they are cleared and must be signed
+    /// again by the caller if needed
+    pub fn for_retry(&self, new_server_data: ServerData, signing_key: &SigningKey) -> Self {
+        let mut request = ClientRequest::new(
+            self.client_params.clone(),
+            new_server_data,
+            Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+        );
+        request.identity_signature = signing_key.sign(request.signed_bytes().as_bytes());
+
+        request
     }
 
     /// Parse a client request from a query string
+    ///
+    /// The client, server, ids, pids and urs values may appear in any order.
     pub fn from_query_string(query_string: &str) -> Result<Self> {
-        let map = parse_query_data(query_string)?;
+        Self::from_query_map(parse_query_data(query_string)?, decode_signature)
+    }
+
+    /// Parse a client request from a query string, rejecting any signature
+    /// with a non-canonical S value as soon as it is decoded
+    ///
+    /// See [`decode_signature_strict`](crate::decode_signature_strict) for
+    /// why such signatures matter. Verification rejects them either way.
+    pub fn from_query_string_strict(query_string: &str) -> Result<Self> {
+        Self::from_query_map(parse_query_data(query_string)?, decode_signature_strict)
+    }
+
+    /// Parse a client request from the body of an HTTP POST sent as
+    /// `application/x-www-form-urlencoded`, ignoring surrounding whitespace
+    /// such as a trailing newline
+    pub fn from_form_body(body: &str) -> Result<Self> {
+        Self::from_query_map(parse_form_data(body)?, decode_signature)
+    }
+
+    /// Parse a client request from the body of an HTTP POST, rejecting any
+    /// signature with a non-canonical S value like
+    /// [`from_query_string_strict`](Self::from_query_string_strict)
+    pub fn from_form_body_strict(body: &str) -> Result<Self> {
+        Self::from_query_map(parse_form_data(body)?, decode_signature_strict)
+    }
+
+    fn from_query_map(
+        map: BTreeMap<String, String>,
+        decode_signature: fn(&str) -> Result<Signature>,
+    ) -> Result<Self> {
         let client_parameters_string = get_or_error(
             &map,
             CLIENT_PARAMETERS_KEY,
             "Invalid client request: No client parameters",
         )?;
         let client_params = ClientParameters::from_base64(&client_parameters_string)?;
+        let received_client_params = Some((
+            SecretString::new(client_parameters_string),
+            SecretString::new(client_params.to_base64()),
+        ));
         let server_string = get_or_error(
             &map,
             SERVER_DATA_KEY,
@@ -86,7 +348,14 @@ impl ClientRequest {
             None => None,
         };
 
-        let unlock_request_signature = map.get(UNLOCK_REQUEST_SIGNATURE_KEY).map(|x| x.to_string());
+        // The urs is kept as it was sent, but must still be a signature
+        let unlock_request_signature = match map.get(UNLOCK_REQUEST_SIGNATURE_KEY) {
+            Some(x) => {
+                decode_signature(x)?;
+                Some(x.to_string())
+            }
+            None => None,
+        };
 
         Ok(ClientRequest {
             client_params,
@@ -94,36 +363,119 @@ impl ClientRequest {
             identity_signature,
             previous_identity_signature,
             unlock_request_signature,
+            received_client_params,
         })
     }
 
     /// Convert a client request to the query string to add in the request
+    ///
+    /// A parsed request keeps the client parameters (client) exactly as they
+    /// were received, so re-encoding an unmodified request gives back the
+    /// same bytes. Once the parameters are changed they are encoded again.
     pub fn to_query_string(&self) -> String {
+        let client = self.client_params_base64();
+        let server = self.server_data.to_base64();
+        let ids = encode_signature(&self.identity_signature);
+        let pids = self
+            .previous_identity_signature
+            .as_ref()
+            .map(encode_signature);
+        let mut values = Vec::from([
+            (CLIENT_PARAMETERS_KEY, client.as_str()),
+            (SERVER_DATA_KEY, server.as_str()),
+            (IDENTITY_SIGNATURE_KEY, ids.as_str()),
+        ]);
+        if let Some(pids) = &pids {
+            values.push((PREVIOUS_IDENTITY_SIGNATURE_KEY, pids));
+        }
+        if let Some(urs) = &self.unlock_request_signature {
+            values.push((UNLOCK_REQUEST_SIGNATURE_KEY, urs));
+        }
+
+        // Size the buffer up front, so growing it never leaves a partial
+        // copy of the client parameters behind
+        let length = values
+            .iter()
+            .map(|(key, value)| key.len() + value.len() + 2)
+            .sum();
+        let mut result = String::with_capacity(length);
+        for (key, value) in values {
+            if !result.is_empty() {
+                result.push('&');
+            }
+            result.push_str(key);
+            result.push('=');
+            result.push_str(value);
+        }
+
+        result
+    }
+
+    /// Describe the request for logging without its secrets. The command,
+    /// version, options, button, and nut are shown, keys are shortened to a
+    /// fingerprint, and signatures, index secrets, and unlock keys are
+    /// replaced with `***`.
+    /// ```rust
+    /// use ed25519_dalek::SigningKey;
+    /// use sqrl_protocol::{client_request::ClientRequest, SqrlUrl};
+    ///
+    /// let url = SqrlUrl::parse("sqrl://example.com/cli.sqrl?nut=1234abcd").unwrap();
+    /// let request = ClientRequest::new_query(&url, &SigningKey::from_bytes(&[1; 32]));
+    /// assert_eq!(
+    ///     "cmd=query ver=1 idk=iojj3X… ids=*** nut=1234abcd",
+    ///     request.redacted()
+    /// );
+    /// ```
+    pub fn redacted(&self) -> String {
+        const REDACTED: &str = "***";
+        let params = &self.client_params;
         let mut result = format!(
-            "{}={}",
-            CLIENT_PARAMETERS_KEY,
-            self.client_params.to_base64()
+            "{}={} {}={}",
+            COMMAND_KEY, params.command, PROTOCOL_VERSION_KEY, params.protocol_version
         );
-        result += &format!("&{}={}", SERVER_DATA_KEY, self.server_data);
+        if let Some(options) = &params.options {
+            result += &format!(
+                " {}={}",
+                OPTIONS_KEY,
+                ClientOption::to_option_string(options, &params.unknown_options)
+            );
+        }
+        if let Some(button) = params.button {
+            result += &format!(" {}={}", BUTTON_KEY, button);
+        }
         result += &format!(
-            "&{}={}",
-            IDENTITY_SIGNATURE_KEY,
-            BASE64_URL_SAFE_NO_PAD.encode(self.identity_signature.to_bytes())
+            " {}={}",
+            IDENTITY_KEY_KEY,
+            key_fingerprint(&params.identity_key)
         );
+        if let Some(pidk) = &params.previous_identity_key {
+            result += &format!(" {}={}", PREVIOUS_IDENTITY_KEY_KEY, key_fingerprint(pidk));
+        }
 
-        if let Some(pids) = &self.previous_identity_signature {
-            result += &format!(
-                "&{}={}",
+        let secrets = [
+            (INDEX_SECRET_KEY, params.index_secret.is_some()),
+            (
+                PREVIOUS_INDEX_SECRET_KEY,
+                params.previous_index_secret.is_some(),
+            ),
+            (SERVER_UNLOCK_KEY_KEY, params.server_unlock_key.is_some()),
+            (VERIFY_UNLOCK_KEY_KEY, params.verify_unlock_key.is_some()),
+            (IDENTITY_SIGNATURE_KEY, true),
+            (
                 PREVIOUS_IDENTITY_SIGNATURE_KEY,
-                BASE64_URL_SAFE_NO_PAD.encode(pids.to_bytes())
-            );
-        }
-        if let Some(urs) = &self.unlock_request_signature {
-            result += &format!(
-                "&{}={}",
+                self.previous_identity_signature.is_some(),
+            ),
+            (
                 UNLOCK_REQUEST_SIGNATURE_KEY,
-                BASE64_URL_SAFE_NO_PAD.encode(urs)
-            );
+                self.unlock_request_signature.is_some(),
+            ),
+        ];
+        for (key, _) in secrets.iter().filter(|(_, present)| *present) {
+            result += &format!(" {}={}", key, REDACTED);
+        }
+
+        if let Some(nut) = self.server_nut() {
+            result += &format!(" {}={}", NUT_KEY, nut);
         }
 
         result
@@ -131,14 +483,208 @@ impl ClientRequest {
 
     /// Get the portion of the client request that is signed
     pub fn get_signed_string(&self) -> String {
-        format!(
-            "{}{}",
-            self.client_params.to_base64(),
-            &self.server_data.to_base64()
-        )
+        let client = self.client_params_base64();
+        let server = self.server_data.to_base64();
+        let mut signed_string = String::with_capacity(client.len() + server.len());
+        signed_string.push_str(&client);
+        signed_string.push_str(&server);
+        signed_string
+    }
+
+    // The signed string includes any index secrets, so wipe it once the
+    // signatures have been made or checked
+    fn signed_bytes(&self) -> SecretString {
+        SecretString::new(self.get_signed_string())
+    }
+
+    // Use the client parameters as received unless they have been changed
+    // since, in which case the received encoding no longer applies
+    fn client_params_base64(&self) -> SecretString {
+        let encoded = SecretString::new(self.client_params.to_base64());
+        match &self.received_client_params {
+            Some((received, original)) if *original == encoded => received.clone(),
+            _ => encoded,
+        }
+    }
+
+    /// Validate the client request and then verify its signatures
+    ///
+    /// This is the recommended entry point for servers. The checks run from
+    /// cheapest to most expensive so malformed requests are rejected before
+    /// any ed25519 work is done:
+    /// 1. Decoding and length checks of the keys and signatures, which happen
+    ///    when the request is parsed (e.g. `from_query_string`)
+    /// 2. Structural validation of the fields required for the command
+    ///    (`validate`)
+    /// 3. Signature verification (`verify_signatures`)
+    pub fn verify_and_validate(&self) -> Result<()> {
+        self.validate()?;
+        self.verify_signatures()
+    }
+
+    /// Verify the signatures included in the client request
+    ///
+    /// The identity signature (ids) must always verify against the identity
+    /// key (idk). If a previous identity key (pidk) is present, the previous
+    /// identity signature (pids) must also be present and verify against it,
+    /// otherwise the previous identity is not proven and the request fails.
+    pub fn verify_signatures(&self) -> Result<()> {
+        let signed_string = self.signed_bytes();
+
+        if self
+            .client_params
+            .identity_key
+            .verify_strict(signed_string.as_bytes(), &self.identity_signature)
+            .is_err()
+        {
+            return Err(client_failure(
+                "Identity signature (ids) failed to verify against identity key (idk)".to_owned(),
+            ));
+        }
+
+        if let Some(previous_identity_key) = &self.client_params.previous_identity_key {
+            let previous_identity_signature = match &self.previous_identity_signature {
+                Some(x) => x,
+                None => return Err(client_failure(
+                    "Previous identity key (pidk) set, but no previous identity signature (pids)"
+                        .to_owned(),
+                )),
+            };
+
+            if previous_identity_key
+                .verify_strict(signed_string.as_bytes(), previous_identity_signature)
+                .is_err()
+            {
+                return Err(client_failure(
+                    "Previous identity signature (pids) failed to verify against previous identity key (pidk)"
+                        .to_owned(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Verify the signatures of many client requests at once, which is much
+    /// faster than verifying each request in turn
+    ///
+    /// If any signature fails, the requests are checked one at a time so the
+    /// error names the index of the first request that failed. Weak keys are
+    /// rejected, as with [`ClientRequest::verify_signatures`].
+    ///
+    /// Batch verification only checks a randomly weighted sum of the
+    /// signatures, which is looser than the strict check
+    /// [`ClientRequest::verify_signatures`] makes. Signatures with a
+    /// non-canonical S or R, or a small-order R, are never batched so they
+    /// are still rejected. An R with a small-order component added to it is
+    /// not checked for, since that costs as much as verifying the signature.
+    /// The signer of such a signature can get it accepted here for about one
+    /// in eight batches, even though verifying the request on its own
+    /// rejects it.
+    #[cfg(feature = "batch")]
+    pub fn verify_batch(requests: &[ClientRequest]) -> Result<()> {
+        let signed_strings: Vec<SecretString> = requests.iter().map(|x| x.signed_bytes()).collect();
+        let mut messages: Vec<&[u8]> = Vec::new();
+        let mut signatures = Vec::new();
+        let mut keys = Vec::new();
+        let mut batchable = true;
+        for (request, signed_string) in requests.iter().zip(&signed_strings) {
+            messages.push(signed_string.as_bytes());
+            signatures.push(request.identity_signature);
+            keys.push(request.client_params.identity_key);
+
+            match (
+                &request.client_params.previous_identity_key,
+                &request.previous_identity_signature,
+            ) {
+                (Some(key), Some(signature)) => {
+                    messages.push(signed_string.as_bytes());
+                    signatures.push(*signature);
+                    keys.push(*key);
+                }
+                // A pidk without its pids can't pass, so find it below
+                (Some(_), None) => batchable = false,
+                _ => (),
+            }
+        }
+
+        // Batch verification doesn't check for weak keys or malformed
+        // signatures like verify_strict does
+        batchable &= !keys.iter().any(|x| x.is_weak());
+        batchable &= signatures.iter().all(is_batchable_signature);
+        if batchable && ed25519_dalek::verify_batch(&messages, &signatures, &keys).is_ok() {
+            return Ok(());
+        }
+
+        for (i, request) in requests.iter().enumerate() {
+            if let Err(e) = request.verify_signatures() {
+                return Err(client_failure(format!(
+                    "Request {} in batch failed to verify: {}",
+                    i, e
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Verify the signatures included in the client request on tokio's
+    /// blocking thread pool, so a busy server doesn't stall its async
+    /// handlers on signature checks. The request is handed back once it has
+    /// been verified.
+    /// ```rust
+    /// use ed25519_dalek::SigningKey;
+    /// use sqrl_protocol::{client_request::ClientRequest, SqrlUrl};
+    ///
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// let url = SqrlUrl::parse("sqrl://example.com/cli.sqrl?nut=1234abcd").unwrap();
+    /// let request = ClientRequest::new_query(&url, &SigningKey::from_bytes(&[1; 32]));
+    /// let request = request.verify_signatures_async().await.unwrap();
+    /// request.validate().unwrap();
+    /// # });
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub async fn verify_signatures_async(self) -> Result<Self> {
+        match tokio::task::spawn_blocking(move || self.verify_signatures().map(|_| self)).await {
+            Ok(result) => result,
+            Err(e) => Err(SqrlError::new(format!(
+                "Signature verification task failed: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Verify the unlock request signature (urs) against the verify unlock
+    /// key (vuk) the server stored for the identity, as required for the
+    /// enable and remove commands
+    pub fn verify_unlock_signature(&self, verify_unlock_key: &VerifyingKey) -> Result<()> {
+        let unlock_request_signature = match &self.unlock_request_signature {
+            Some(x) => decode_signature(x)?,
+            None => {
+                return Err(client_failure(
+                    "No unlock request signature (urs) to verify".to_owned(),
+                ))
+            }
+        };
+
+        if verify_unlock_key
+            .verify_strict(self.signed_bytes().as_bytes(), &unlock_request_signature)
+            .is_err()
+        {
+            return Err(client_failure(
+                "Unlock request signature (urs) failed to verify against verify unlock key (vuk)"
+                    .to_owned(),
+            ));
+        }
+
+        Ok(())
     }
 
     /// Validate that the values input in the client request are valid
+    ///
+    /// A previous identity key (pidk) may be sent with any command, including
+    /// a query, but must always be paired with a previous identity signature
+    /// (pids) and differ from the identity key (idk).
     pub fn validate(&self) -> Result<()> {
         self.client_params.validate()?;
 
@@ -146,71 +692,343 @@ impl ClientRequest {
         if self.previous_identity_signature.is_some()
             && self.client_params.previous_identity_key.is_none()
         {
-            return Err(SqrlError::new(
+            return Err(client_failure(
                 "Previous identity signature set, but no previous identity key set".to_owned(),
             ));
         } else if self.previous_identity_signature.is_none()
             && self.client_params.previous_identity_key.is_some()
         {
-            return Err(SqrlError::new(
+            return Err(client_failure(
                 "Previous identity key set, but no previous identity signature".to_owned(),
             ));
         }
 
+        // The previous identity must be a different key than the current one
+        if self.client_params.previous_identity_key == Some(self.client_params.identity_key) {
+            return Err(SqrlError::new(
+                "Previous identity key (pidk) must be different from the identity key (idk)"
+                    .to_owned(),
+            )
+            .with_suggested_tif(TIFValue::BadId));
+        }
+
         // If the enable or remove commands are set, the unlock request signature must also be set
         if (self.client_params.command == ClientCommand::Enable
             || self.client_params.command == ClientCommand::Remove)
             && self.unlock_request_signature.is_none()
         {
-            return Err(SqrlError::new(
+            return Err(client_failure(
                 "When attempting to enable identity, unlock request signature (urs) must be set"
                     .to_owned(),
             ));
         }
 
-        match &self.server_data {
+        // If the server asked a question, the button must be one it offered
+        if let (
+            Some(button),
             ServerData::ServerResponse {
                 server_response, ..
-            } if !server_response
-                .transaction_indication_flags
-                .contains(&TIFValue::CurrentIdMatch) =>
-            {
-                if self.client_params.server_unlock_key.is_none() {
-                    return Err(SqrlError::new("If attempting to re-enable identity (cmd=enable), must include server unlock key (suk)".to_owned()));
-                } else if self.client_params.verify_unlock_key.is_none() {
-                    return Err(SqrlError::new("If attempting to re-enable identity (cmd=enable), must include verify unlock key (vuk)".to_owned()));
+            },
+        ) = (self.client_params.button, &self.server_data)
+        {
+            let ask = server_response
+                .parsed_ask()
+                .map_err(|e| client_failure(format!("Invalid ask in the server data: {}", e)))?;
+            if let Some(ask) = ask {
+                let buttons = ask.buttons().len();
+                if button as usize > buttons {
+                    return Err(client_failure(format!(
+                        "Button (btn) {} does not match a button in the server's ask ({} buttons)",
+                        button, buttons
+                    )));
                 }
             }
-            _ => (),
+        }
+
+        if requires_unlock_keys(self.client_params.command, &self.server_data) {
+            if self.client_params.server_unlock_key.is_none() {
+                return Err(client_failure(
+                    "When associating a new identity (cmd=ident), must include server unlock key (suk)"
+                        .to_owned(),
+                ));
+            } else if self.client_params.verify_unlock_key.is_none() {
+                return Err(client_failure(
+                    "When associating a new identity (cmd=ident), must include verify unlock key (vuk)"
+                        .to_owned(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Associating an identity the server doesn't know yet (no CurrentIdMatch in
+// its last response) requires the unlock keys, so the server can later
+// verify an enable or remove. Shared by the request and builder validation so
+// they can't disagree.
+fn requires_unlock_keys(command: ClientCommand, server_data: &ServerData) -> bool {
+    match server_data {
+        ServerData::ServerResponse {
+            server_response, ..
+        } => {
+            command == ClientCommand::Ident
+                && !server_response
+                    .transaction_indication_flags
+                    .contains(&TIFValue::CurrentIdMatch)
+        }
+        ServerData::Url { .. } => false,
+    }
+}
+
+// A short, recognizable prefix of a public key for logs
+fn key_fingerprint(key: &VerifyingKey) -> String {
+    let encoded = encode_public_key(key);
+    format!("{}…", &encoded[..6])
+}
+
+// Only batch signatures with a canonical S and an R that is canonically
+// encoded and not of small order, which verify_strict would reject
+#[cfg(feature = "batch")]
+fn is_batchable_signature(signature: &Signature) -> bool {
+    let r = CompressedEdwardsY(*signature.r_bytes());
+    is_canonical_scalar(signature.s_bytes())
+        && match r.decompress() {
+            Some(point) => point.compress() == r && !point.is_small_order(),
+            None => false,
+        }
+}
+
+// An error rejecting a malformed client request, which servers should answer
+// with the ClientFailure flag
+fn client_failure(error: String) -> SqrlError {
+    SqrlError::new(error).with_suggested_tif(TIFValue::ClientFailure)
+}
+
+/// A builder for client requests that checks the fields required by the
+/// command are present before signing the request
+///
+/// The signing keys held by the builder are wiped when dropped. With the
+/// `zeroize` feature the index secrets are wiped as well, since they are
+/// kept as [`SecretString`]s.
+pub struct ClientRequestBuilder {
+    command: ClientCommand,
+    server_data: ServerData,
+    protocol_version: Option<ProtocolVersion>,
+    options: Option<Vec<ClientOption>>,
+    button: Option<u8>,
+    index_secret: Option<SecretString>,
+    previous_index_secret: Option<SecretString>,
+    server_unlock_key: Option<String>,
+    verify_unlock_key: Option<String>,
+    previous_identity: Option<SigningKey>,
+    unlock_request_key: Option<SigningKey>,
+}
+
+impl ClientRequestBuilder {
+    /// Start building a client request for the command and server data
+    pub fn new(command: ClientCommand, server_data: ServerData) -> Self {
+        ClientRequestBuilder {
+            command,
+            server_data,
+            protocol_version: None,
+            options: None,
+            button: None,
+            index_secret: None,
+            previous_index_secret: None,
+            server_unlock_key: None,
+            verify_unlock_key: None,
+            previous_identity: None,
+            unlock_request_key: None,
+        }
+    }
+
+    /// Set the options requested by the client (opt)
+    pub fn options(mut self, options: Vec<ClientOption>) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    /// Set the protocol versions the client supports (ver), which default to
+    /// [`PROTOCOL_VERSIONS`]
+    ///
+    /// Clients should advertise their full supported range rather than a
+    /// version learned from a previous response, and let the server pick
+    /// the highest version both sides support.
+    pub fn protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = Some(protocol_version);
+        self
+    }
+
+    /// Set the button pressed in response to a server query (btn)
+    pub fn button(mut self, button: u8) -> Self {
+        self.button = Some(button);
+        self
+    }
+
+    /// Set the current identity indexed secret (ins)
+    pub fn index_secret(mut self, index_secret: String) -> Self {
+        self.index_secret = Some(SecretString::new(index_secret));
+        self
+    }
+
+    /// Set the previous identity indexed secret (pins)
+    pub fn previous_index_secret(mut self, previous_index_secret: String) -> Self {
+        self.previous_index_secret = Some(SecretString::new(previous_index_secret));
+        self
+    }
+
+    /// Set the server unlock key (suk)
+    pub fn server_unlock_key(mut self, server_unlock_key: String) -> Self {
+        self.server_unlock_key = Some(server_unlock_key);
+        self
+    }
+
+    /// Set the verify unlock key (vuk)
+    pub fn verify_unlock_key(mut self, verify_unlock_key: String) -> Self {
+        self.verify_unlock_key = Some(verify_unlock_key);
+        self
+    }
+
+    /// Set the previous identity, used to set the pidk and sign the pids
+    ///
+    /// On a query, this asks the server whether it knows the previous
+    /// identity, which it reports with the PreviousIdMatch flag. The client
+    /// can then send an ident signed by both identities to update the server
+    /// to its current identity.
+    pub fn previous_identity(mut self, previous_identity: SigningKey) -> Self {
+        self.previous_identity = Some(previous_identity);
+        self
+    }
+
+    /// Set the unlock request signing key, used to sign the urs
+    pub fn unlock_request_key(mut self, unlock_request_key: SigningKey) -> Self {
+        self.unlock_request_key = Some(unlock_request_key);
+        self
+    }
+
+    /// Verify that all fields required by the command have been supplied,
+    /// and that any unlock keys and index secrets are 32 base64url encoded
+    /// bytes as the server requires
+    pub fn validate(&self) -> Result<()> {
+        // The enable and remove commands must be signed by the unlock request key
+        if (self.command == ClientCommand::Enable || self.command == ClientCommand::Remove)
+            && self.unlock_request_key.is_none()
+        {
+            return Err(SqrlError::new(format!(
+                "Unable to build {} request: unlock request key (urs) must be set",
+                self.command
+            )));
+        }
+
+        // The keys and secrets must be valid, or the server can't parse the request
+        let fields = [
+            (INDEX_SECRET_KEY, self.index_secret.as_deref()),
+            (
+                PREVIOUS_INDEX_SECRET_KEY,
+                self.previous_index_secret.as_deref(),
+            ),
+            (SERVER_UNLOCK_KEY_KEY, self.server_unlock_key.as_deref()),
+            (VERIFY_UNLOCK_KEY_KEY, self.verify_unlock_key.as_deref()),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                check_base64_field(key, value, KEY_LENGTH)?;
+            }
+        }
+
+        if requires_unlock_keys(self.command, &self.server_data) {
+            if self.server_unlock_key.is_none() {
+                return Err(SqrlError::new(
+                    "Unable to build ident request for a new identity: server unlock key (suk) must be set".to_owned(),
+                ));
+            } else if self.verify_unlock_key.is_none() {
+                return Err(SqrlError::new(
+                    "Unable to build ident request for a new identity: verify unlock key (vuk) must be set".to_owned(),
+                ));
+            }
         }
 
         Ok(())
     }
+
+    /// Build the client request and sign it with the identity (and any
+    /// previous identity or unlock request key supplied)
+    pub fn build_and_sign(mut self, identity: &SigningKey) -> Result<ClientRequest> {
+        self.validate()?;
+
+        let mut client_params = ClientParameters::new(self.command, identity.verifying_key());
+        if let Some(protocol_version) = self.protocol_version.take() {
+            client_params.protocol_version = protocol_version;
+        }
+        client_params.options = self.options.take();
+        client_params.button = self.button;
+        client_params.previous_identity_key =
+            self.previous_identity.as_ref().map(|x| x.verifying_key());
+        client_params.index_secret = self.index_secret.take();
+        client_params.previous_index_secret = self.previous_index_secret.take();
+        client_params.server_unlock_key = self.server_unlock_key.take();
+        client_params.verify_unlock_key = self.verify_unlock_key.take();
+
+        let mut request = ClientRequest::new(
+            client_params,
+            self.server_data.clone(),
+            Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+        );
+        let signed_string = request.signed_bytes();
+        request.identity_signature = identity.sign(signed_string.as_bytes());
+        request.previous_identity_signature = self
+            .previous_identity
+            .as_ref()
+            .map(|x| x.sign(signed_string.as_bytes()));
+        request.unlock_request_signature = self
+            .unlock_request_key
+            .as_ref()
+            .map(|x| encode_signature(&x.sign(signed_string.as_bytes())));
+
+        Ok(request)
+    }
 }
 
 /// Parameters used for sending requests to the client
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClientParameters {
-    /// The supported protocol versions of the client (ver)
+    /// The supported protocol versions of the client (ver). This is the
+    /// client's full supported range, which the server negotiates against.
     pub protocol_version: ProtocolVersion,
     /// The client command requested to be performed (cmd)
     pub command: ClientCommand,
     /// The client identity used to sign the request (idk)
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_public_key"))]
     pub identity_key: VerifyingKey,
     /// Optional options requested by the client (opt)
+    ///
+    /// An empty list is treated the same as `None`: no `opt` key is encoded,
+    /// and parsing an empty `opt=` gives `None`
     pub options: Option<Vec<ClientOption>>,
     /// The button pressed in response to a server query (btn)
     pub button: Option<u8>,
     /// A previous client identity used to sign the request (pidk)
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "crate::serde_public_key::option")
+    )]
     pub previous_identity_key: Option<VerifyingKey>,
-    /// The current identity indexed secret in response to a server query (ins)
-    pub index_secret: Option<String>,
-    /// The previous identity indexed secret in response to a server query (pins)
-    pub previous_index_secret: Option<String>,
+    /// The current identity indexed secret in response to a server query
+    /// (ins). With the `zeroize` feature it is wiped when dropped.
+    pub index_secret: Option<SecretString>,
+    /// The previous identity indexed secret in response to a server query
+    /// (pins). With the `zeroize` feature it is wiped when dropped.
+    pub previous_index_secret: Option<SecretString>,
     /// The server unlock key used for unlocking an identity (suk)
     pub server_unlock_key: Option<String>,
     /// The verify unlock key used for unlocking an identity (vuk)
     pub verify_unlock_key: Option<String>,
+    /// Any options sent by the client that aren't understood, which are
+    /// ignored but kept so the parameters re-encode identically (opt)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unknown_options: Vec<String>,
 }
 
 impl ClientParameters {
@@ -227,67 +1045,153 @@ impl ClientParameters {
             previous_index_secret: None,
             server_unlock_key: None,
             verify_unlock_key: None,
+            unknown_options: Vec::new(),
         }
     }
 
+    /// Get the raw bytes of the identity key (idk)
+    pub fn identity_key_bytes(&self) -> [u8; 32] {
+        self.identity_key.to_bytes()
+    }
+
     /// Parse a base64-encoded client parameter value
     pub fn from_base64(base64_string: &str) -> Result<Self> {
-        let query_string = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(base64_string)?)?;
+        let query_string = SecretString::new(decode_base64_payload(base64_string)?);
         Self::from_str(&query_string)
     }
 
     /// base64-encode this client parameter object
     pub fn to_base64(&self) -> String {
-        BASE64_URL_SAFE_NO_PAD.encode(self.to_string().as_bytes())
+        BASE64_URL_SAFE_NO_PAD.encode(self.encode().as_bytes())
+    }
+
+    /// Whether the client requested the given option, treating no options
+    /// (opt) as none being set
+    pub fn has_option(&self, option: ClientOption) -> bool {
+        self.options
+            .as_ref()
+            .is_some_and(|options| options.contains(&option))
+    }
+
+    // Whether any known option is set, treating an empty list as none
+    fn has_any_option(&self) -> bool {
+        self.options
+            .as_ref()
+            .is_some_and(|options| !options.is_empty())
+    }
+
+    /// Whether the client has a secure way to return the user's browser to
+    /// the site (opt=cps)
+    pub fn wants_cps(&self) -> bool {
+        self.has_option(ClientOption::ClientProvidedSession)
+    }
+
+    /// Whether the client asked the server to only allow SQRL authentication
+    /// (opt=sqrlonly)
+    ///
+    /// This protects against downgrade attacks, so the server must persist it
+    /// (see [`ClientParameters::account_flags`]) and refuse any non-SQRL login
+    /// for the account while it is set. Clients keep asserting it on every
+    /// request, and a server should only clear it when a successful ident
+    /// request omits it.
+    /// ```rust
+    /// use sqrl_protocol::client_request::{ClientCommand, ClientOption, ClientParameters};
+    /// use ed25519_dalek::SigningKey;
+    ///
+    /// let identity = SigningKey::from_bytes(&[1; 32]).verifying_key();
+    /// let mut params = ClientParameters::new(ClientCommand::Ident, identity);
+    /// assert!(!params.sqrl_only());
+    ///
+    /// params.options = Some(vec![ClientOption::SQRLOnly]);
+    /// assert!(params.sqrl_only());
+    /// ```
+    pub fn sqrl_only(&self) -> bool {
+        self.has_option(ClientOption::SQRLOnly)
+    }
+
+    /// Whether the client asked the server to block side-channel identity
+    /// changes (opt=hardlock)
+    pub fn hardlock(&self) -> bool {
+        self.has_option(ClientOption::Hardlock)
+    }
+
+    /// Get the options that represent durable account state, which the
+    /// server should store on the identity's record when the request
+    /// succeeds
+    pub fn account_flags(&self) -> AccountFlags {
+        AccountFlags {
+            sqrl_only: self.sqrl_only(),
+            hardlock: self.hardlock(),
+        }
     }
 
     /// Verify the client request is valid
     pub fn validate(&self) -> Result<()> {
+        // Only buttons 1 and 2 are defined, with 0 meaning the ask was dismissed
+        if let Some(button) = self.button {
+            if button > MAX_BUTTON {
+                return Err(client_failure(format!(
+                    "Invalid client request: Button (btn) {} must be between 0 and {}",
+                    button, MAX_BUTTON
+                )));
+            }
+        }
+
         Ok(())
     }
 }
 
 impl fmt::Display for ClientParameters {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut map = HashMap::<&str, &str>::new();
+        write!(f, "{}", self.encode().as_str())
+    }
+}
+
+impl ClientParameters {
+    // Encode the parameters as newline data, which includes any index secrets
+    fn encode(&self) -> SecretString {
+        let mut map = Vec::<(&str, &str)>::new();
         let protocol = self.protocol_version.to_string();
-        map.insert(PROTOCOL_VERSION_KEY, &protocol);
+        map.push((PROTOCOL_VERSION_KEY, &protocol));
         let command = self.command.to_string();
-        map.insert(COMMAND_KEY, &command);
+        map.push((COMMAND_KEY, &command));
 
-        let identity_key = BASE64_URL_SAFE_NO_PAD.encode(self.identity_key.as_bytes());
-        map.insert(IDENTITY_KEY_KEY, &identity_key);
+        let identity_key = encode_public_key(&self.identity_key);
+        map.push((IDENTITY_KEY_KEY, &identity_key));
 
         let options_string: String;
-        if let Some(options) = &self.options {
-            options_string = ClientOption::to_option_string(options);
-            map.insert(OPTIONS_KEY, &options_string);
+        // An empty opt= is malformed, so only encode it if there are options
+        if self.has_any_option() || !self.unknown_options.is_empty() {
+            options_string = ClientOption::to_option_string(
+                self.options.as_deref().unwrap_or_default(),
+                &self.unknown_options,
+            );
+            map.push((OPTIONS_KEY, &options_string));
         }
         let button_string: String;
         if let Some(button) = &self.button {
             button_string = button.to_string();
-            map.insert(BUTTON_KEY, &button_string);
+            map.push((BUTTON_KEY, &button_string));
         }
         let previous_identity_key_string: String;
         if let Some(previous_identity_key) = &self.previous_identity_key {
-            previous_identity_key_string =
-                BASE64_URL_SAFE_NO_PAD.encode(previous_identity_key.as_bytes());
-            map.insert(PREVIOUS_IDENTITY_KEY_KEY, &previous_identity_key_string);
+            previous_identity_key_string = encode_public_key(previous_identity_key);
+            map.push((PREVIOUS_IDENTITY_KEY_KEY, &previous_identity_key_string));
         }
         if let Some(index_secret) = &self.index_secret {
-            map.insert(INDEX_SECRET_KEY, index_secret);
+            map.push((INDEX_SECRET_KEY, index_secret.as_str()));
         }
         if let Some(previous_index_secret) = &self.previous_index_secret {
-            map.insert(PREVIOUS_INDEX_SECRET_KEY, previous_index_secret);
+            map.push((PREVIOUS_INDEX_SECRET_KEY, previous_index_secret.as_str()));
         }
         if let Some(server_unlock_key) = &self.server_unlock_key {
-            map.insert(SERVER_UNLOCK_KEY_KEY, server_unlock_key);
+            map.push((SERVER_UNLOCK_KEY_KEY, server_unlock_key));
         }
         if let Some(verify_unlock_key) = &self.verify_unlock_key {
-            map.insert(VERIFY_UNLOCK_KEY_KEY, verify_unlock_key);
+            map.push((VERIFY_UNLOCK_KEY_KEY, verify_unlock_key));
         }
 
-        write!(f, "{}", &encode_newline_data(&map))
+        SecretString::new(encode_newline_data(&map))
     }
 }
 
@@ -295,7 +1199,7 @@ impl FromStr for ClientParameters {
     type Err = SqrlError;
 
     fn from_str(s: &str) -> result::Result<Self, Self::Err> {
-        let map = parse_newline_data(s)?;
+        let mut map = parse_newline_data(s, "client parameters")?;
         // Validate the protocol version is supported
         let ver_string = get_or_error(
             &map,
@@ -305,7 +1209,7 @@ impl FromStr for ClientParameters {
         let protocol_version = ProtocolVersion::new(&ver_string)?;
 
         let cmd_string = get_or_error(&map, COMMAND_KEY, "Invalid client request: No cmd value")?;
-        let command = ClientCommand::from(cmd_string);
+        let command = ClientCommand::from_str(&cmd_string)?;
         let idk_string = get_or_error(
             &map,
             IDENTITY_KEY_KEY,
@@ -331,15 +1235,22 @@ impl FromStr for ClientParameters {
             None => None,
         };
 
-        let options = match map.get(OPTIONS_KEY) {
-            Some(x) => Some(ClientOption::from_option_string(x)?),
-            None => None,
+        let (options, unknown_options) = match map.get(OPTIONS_KEY) {
+            Some(x) => {
+                let (options, unknown_options) = ClientOption::from_option_string(x);
+                if options.is_empty() {
+                    (None, unknown_options)
+                } else {
+                    (Some(options), unknown_options)
+                }
+            }
+            None => (None, Vec::new()),
         };
 
-        let index_secret = map.get(INDEX_SECRET_KEY).map(|x| x.to_string());
-        let previous_index_secret = map.get(PREVIOUS_INDEX_SECRET_KEY).map(|x| x.to_string());
-        let server_unlock_key = map.get(SERVER_UNLOCK_KEY_KEY).map(|x| x.to_string());
-        let verify_unlock_key = map.get(VERIFY_UNLOCK_KEY_KEY).map(|x| x.to_string());
+        let index_secret = take_secret_field(&mut map, INDEX_SECRET_KEY)?;
+        let previous_index_secret = take_secret_field(&mut map, PREVIOUS_INDEX_SECRET_KEY)?;
+        let server_unlock_key = get_base64_field(&map, SERVER_UNLOCK_KEY_KEY, KEY_LENGTH)?;
+        let verify_unlock_key = get_base64_field(&map, VERIFY_UNLOCK_KEY_KEY, KEY_LENGTH)?;
 
         Ok(ClientParameters {
             protocol_version,
@@ -352,25 +1263,106 @@ impl FromStr for ClientParameters {
             previous_index_secret,
             server_unlock_key,
             verify_unlock_key,
+            unknown_options,
         })
     }
 }
 
-/// The commands a client can request of the server
-#[derive(Debug, PartialEq)]
-pub enum ClientCommand {
-    /// A query to determine which client identity the server knows
-    Query,
-    /// A request to verify and accept the client's identity assertion
-    Ident,
-    /// A request to disable the client identity on the server
-    Disable,
-    /// A request to re-enable the client identity on the server
+// Move an index secret out of the parsed values rather than copying it, so
+// no unwiped copy is freed along with the map
+fn take_secret_field(
+    map: &mut BTreeMap<String, String>,
+    key: &str,
+) -> Result<Option<SecretString>> {
+    match map.remove(key) {
+        Some(value) => {
+            let value = SecretString::new(value);
+            check_base64_field(key, &value, KEY_LENGTH)?;
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The client options that a server persists as account settings, rather
+/// than applying only to the current request
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccountFlags {
+    /// Only allow SQRL authentication for the account (sqrlonly)
+    pub sqrl_only: bool,
+    /// Block side-channel changes to the account's identity (hardlock)
+    pub hardlock: bool,
+}
+
+/// The options a server enforces regardless of what a client requests
+///
+/// If an option is both required and refused, requiring it wins.
+/// ```rust
+/// use sqrl_protocol::client_request::{ClientOption, OptionPolicy};
+///
+/// let policy = OptionPolicy {
+///     required: vec![ClientOption::SQRLOnly],
+///     refused: vec![ClientOption::Hardlock],
+/// };
+/// assert_eq!(
+///     vec![ClientOption::SQRLOnly, ClientOption::ClientProvidedSession],
+///     policy.merge(&[ClientOption::Hardlock, ClientOption::ClientProvidedSession])
+/// );
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OptionPolicy {
+    /// Options applied even if the client didn't request them
+    pub required: Vec<ClientOption>,
+    /// Options the server won't honor, even if the client requested them
+    pub refused: Vec<ClientOption>,
+}
+
+impl OptionPolicy {
+    /// Combine the options the client requested with the server's policy,
+    /// returning the options in effect in their canonical order
+    pub fn merge(&self, requested: &[ClientOption]) -> Vec<ClientOption> {
+        let mut options: Vec<ClientOption> = requested
+            .iter()
+            .filter(|x| !self.refused.contains(x))
+            .copied()
+            .collect();
+        options.extend(self.required.iter().copied());
+
+        ClientOption::canonicalize(&options)
+    }
+}
+
+/// The commands a client can request of the server
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ClientCommand {
+    /// A query to determine which client identity the server knows
+    Query,
+    /// A request to verify and accept the client's identity assertion
+    Ident,
+    /// A request to disable the client identity on the server
+    Disable,
+    /// A request to re-enable the client identity on the server
     Enable,
     /// A request to remove the client identity from the server
     Remove,
 }
 
+impl ClientCommand {
+    /// Whether the command must follow a query in the same SQRL transaction
+    ///
+    /// A client always starts with a query so the server can report what it
+    /// knows about the identity. Every other command is only valid as a
+    /// follow up to that query, so servers should reject them when no query
+    /// was received for the transaction's nut.
+    pub fn requires_prior_query(&self) -> bool {
+        !matches!(self, ClientCommand::Query)
+    }
+}
+
 impl fmt::Display for ClientCommand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -383,21 +1375,31 @@ impl fmt::Display for ClientCommand {
     }
 }
 
-impl From<String> for ClientCommand {
-    fn from(value: String) -> Self {
-        match value.as_str() {
-            "query" => ClientCommand::Query,
-            "ident" => ClientCommand::Ident,
-            "disable" => ClientCommand::Disable,
-            "enable" => ClientCommand::Enable,
-            "remove" => ClientCommand::Remove,
-            _ => panic!("Not this!"),
+impl FromStr for ClientCommand {
+    type Err = SqrlError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "query" => Ok(ClientCommand::Query),
+            "ident" => Ok(ClientCommand::Ident),
+            "disable" => Ok(ClientCommand::Disable),
+            "enable" => Ok(ClientCommand::Enable),
+            "remove" => Ok(ClientCommand::Remove),
+            _ => Err(client_failure(format!(
+                "Invalid client request: Unknown command (cmd) {}",
+                value
+            ))),
         }
     }
 }
 
 /// Request options included in a client request
-#[derive(Debug, PartialEq)]
+///
+/// Options are kept in declaration order when parsed or encoded, so the same
+/// set of options always produces the same (signed) string
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum ClientOption {
     /// A request to the server to not restrict client requests from only the
     /// ip address that initially queried the server
@@ -409,32 +1411,45 @@ pub enum ClientOption {
     Hardlock,
     /// An option to inform the server that the SQRL client has a secure method
     /// of sending data back to the client's web browser
+    #[cfg_attr(feature = "serde", serde(rename = "cps"))]
     ClientProvidedSession,
     /// A request to the server to return the client identity's server unlock
     /// key
+    #[cfg_attr(feature = "serde", serde(rename = "suk"))]
     ServerUnlockKey,
 }
 
 impl ClientOption {
-    fn from_option_string(opt: &str) -> Result<Vec<Self>> {
+    // Options that aren't understood are returned separately, so they can be
+    // ignored without failing the whole request
+    fn from_option_string(opt: &str) -> (Vec<Self>, Vec<String>) {
         let mut options: Vec<ClientOption> = Vec::new();
-        for option in opt.split('~') {
-            options.push(ClientOption::try_from(option)?)
+        let mut unknown_options: Vec<String> = Vec::new();
+        for option in opt.split('~').filter(|x| !x.is_empty()) {
+            match ClientOption::from_str(option) {
+                Ok(x) => options.push(x),
+                Err(_) => unknown_options.push(option.to_owned()),
+            }
         }
 
-        Ok(options)
+        (Self::canonicalize(&options), unknown_options)
     }
 
-    fn to_option_string(opt: &Vec<Self>) -> String {
-        let mut options = "".to_owned();
-        for option in opt {
-            if options.is_empty() {
-                options += &format!("{}", option);
-            } else {
-                options += &format!("~{}", option);
-            }
-        }
+    fn to_option_string(opt: &[Self], unknown_options: &[String]) -> String {
+        let mut options: Vec<String> = Self::canonicalize(opt)
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        options.extend(unknown_options.iter().cloned());
+
+        options.join("~")
+    }
 
+    // Remove any duplicate options and sort them into their canonical order
+    fn canonicalize(opt: &[Self]) -> Vec<Self> {
+        let mut options = opt.to_vec();
+        options.sort();
+        options.dedup();
         options
     }
 }
@@ -451,10 +1466,10 @@ impl fmt::Display for ClientOption {
     }
 }
 
-impl TryFrom<&str> for ClientOption {
-    type Error = SqrlError;
+impl FromStr for ClientOption {
+    type Err = SqrlError;
 
-    fn try_from(value: &str) -> Result<Self> {
+    fn from_str(value: &str) -> Result<Self> {
         match value {
             "noiptest" => Ok(ClientOption::NoIPTest),
             "sqrlonly" => Ok(ClientOption::SQRLOnly),
@@ -468,7 +1483,7 @@ impl TryFrom<&str> for ClientOption {
 
 /// The previous server response to add to the next client request, or the
 /// SQRL url for the first request
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ServerData {
     /// During the first request sent to a server, the server data is set as
     /// the first SQRL protocol url used to auth against the server
@@ -489,8 +1504,10 @@ pub enum ServerData {
 impl ServerData {
     /// Parse the base64-encoded server data
     pub fn from_base64(base64_string: &str) -> Result<Self> {
-        let data = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(base64_string)?)?;
-        if let Ok(parsed) = SqrlUrl::parse(&data) {
+        let data = decode_base64_payload(base64_string)?;
+        if let Ok(mut parsed) = SqrlUrl::parse(&data) {
+            // Keep the url as received, since that is what the client signed
+            parsed.received = Some(base64_string.to_owned());
             return Ok(ServerData::Url { url: parsed });
         }
 
@@ -503,10 +1520,79 @@ impl ServerData {
         }
     }
 
+    /// Create the server data from a previously parsed server response,
+    /// echoing it as received if it is unchanged
+    pub fn from_response(response: ServerResponse) -> Self {
+        let original_response = response.to_client_server_data();
+        ServerData::ServerResponse {
+            server_response: response,
+            original_response,
+        }
+    }
+
+    /// Get the nut the client is responding to, from either the SQRL url's
+    /// query or the previous server response. Returns `None` if the url has
+    /// no valid nut.
+    pub fn nut(&self) -> Option<Nut> {
+        match self {
+            ServerData::Url { url } => url.nut().ok(),
+            ServerData::ServerResponse {
+                server_response, ..
+            } => Some(server_response.nut.clone()),
+        }
+    }
+
+    /// Check the server data echoed by the client is exactly the response the
+    /// server previously sent, so it wasn't tampered with between requests
+    pub fn matches_response(&self, expected: &ServerResponse) -> bool {
+        match self {
+            ServerData::Url { .. } => false,
+            ServerData::ServerResponse {
+                original_response, ..
+            } => *original_response == expected.to_base64(),
+        }
+    }
+
+    /// Describe the decoded server data for debugging. Unlike `Display`,
+    /// which shows the data as sent, a previous server response is shown
+    /// with its nut, tif flags and qry decoded.
+    ///
+    /// ```rust
+    /// use sqrl_protocol::client_request::ServerData;
+    ///
+    /// let data = ServerData::from_base64("dmVyPTENCm51dD0xV005bGZGMVNULXoNCnRpZj01DQpxcnk9L2NsaS5zcXJsP251dD0xV005bGZGMVNULXoNCnN1az1CTUZEbTdiUGxzUW9qdUpzb0RUdmxTMU1jbndnU2N2a3RGODR2TGpzY0drDQo").unwrap();
+    /// assert_eq!(
+    ///     "response ver=1 nut=1WM9lfF1ST-z tif=0x5 [CurrentIdMatch, IpsMatch] qry=/cli.sqrl?nut=1WM9lfF1ST-z",
+    ///     data.describe()
+    /// );
+    /// ```
+    pub fn describe(&self) -> String {
+        match self {
+            ServerData::Url { url } => format!("url {}", url),
+            ServerData::ServerResponse {
+                server_response, ..
+            } => {
+                let flags = server_response.tif_flags();
+                format!(
+                    "response {}={} {}={} {}={:#x} {:?} {}={}",
+                    PROTOCOL_VERSION_KEY,
+                    server_response.protocol_version,
+                    NUT_KEY,
+                    server_response.nut.as_str(),
+                    TIF_KEY,
+                    flags.bits(),
+                    flags.to_values(),
+                    QUERY_URL_KEY,
+                    server_response.query_url
+                )
+            }
+        }
+    }
+
     /// base64-encode the server data
     pub fn to_base64(&self) -> String {
         match self {
-            ServerData::Url { url } => BASE64_URL_SAFE_NO_PAD.encode(url.to_string().as_bytes()),
+            ServerData::Url { url } => url.to_base64(),
             ServerData::ServerResponse {
                 original_response, ..
             } => original_response.clone(),
@@ -514,6 +1600,12 @@ impl ServerData {
     }
 }
 
+impl From<SqrlUrl> for ServerData {
+    fn from(url: SqrlUrl) -> Self {
+        ServerData::Url { url }
+    }
+}
+
 impl fmt::Display for ServerData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -532,6 +1624,7 @@ impl fmt::Display for ServerData {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server_response::{IdentityState, QueryUrl};
 
     const TEST_CLIENT_REQUEST: &str = "client=dmVyPTENCmNtZD1xdWVyeQ0KaWRrPWlnZ2N1X2UtdFdxM3NvZ2FhMmFBRENzeFJaRUQ5b245SDcxNlRBeVBSMHcNCnBpZGs9RTZRczJnWDdXLVB3aTlZM0tBbWJrdVlqTFNXWEN0S3lCY3ltV2xvSEF1bw0Kb3B0PWNwc35zdWsNCg&server=c3FybDovL3Nxcmwuc3RldmUuY29tL2NsaS5zcXJsP3g9MSZudXQ9ZTd3ZTZ3Q3RvU3hsJmNhbj1hSFIwY0hNNkx5OXNiMk5oYkdodmMzUXZaR1Z0Ynk1MFpYTjA&ids=hcXWTPx3EgP9R_AjtoCIrie_YgZxVD72nd5_pjMOnhUEYmhdjLUYs3jjcJT_GQuzNKXyAwY1ns1R6QJn1YKzCA";
     const TEST_CLIENT_PARAMS: &str = "dmVyPTENCmNtZD1xdWVyeQ0KaWRrPWlnZ2N1X2UtdFdxM3NvZ2FhMmFBRENzeFJaRUQ5b245SDcxNlRBeVBSMHcNCnBpZGs9RTZRczJnWDdXLVB3aTlZM0tBbWJrdVlqTFNXWEN0S3lCY3ltV2xvSEF1bw0Kb3B0PWNwc35zdWsNCg";
@@ -539,11 +1632,868 @@ mod tests {
     const TEST_SQRL_URL: &str = "c3FybDovL3Rlc3R1cmwuY29t";
     const TEST_INVALID_URL: &str = "aHR0cHM6Ly9nb29nbGUuY29t";
 
+    // Fold the group order into the S half of a signature, giving another
+    // encoding of it that only the strict parsers reject
+    fn malleate_signature(mut signature: [u8; 64]) -> String {
+        let order: [u8; 32] = [
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9,
+            0xde, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+        ];
+        let mut carry = 0u16;
+        for (byte, order) in signature[32..].iter_mut().zip(order.iter()) {
+            let sum = *byte as u16 + *order as u16 + carry;
+            *byte = sum as u8;
+            carry = sum >> 8;
+        }
+        BASE64_URL_SAFE_NO_PAD.encode(signature)
+    }
+
+    // The identity the tests sign requests with
+    fn test_identity() -> SigningKey {
+        SigningKey::from_bytes(&[1; 32])
+    }
+
+    // A previous server response with the given flags, for the nut 1WM9lfF1ST-z
+    fn test_response(flags: &[TIFValue]) -> ServerResponse {
+        ServerResponse::new(
+            Nut::parse("1WM9lfF1ST-z").unwrap(),
+            flags.to_vec(),
+            QueryUrl::parse("/cli.sqrl?nut=1WM9lfF1ST-z").unwrap(),
+        )
+    }
+
     #[test]
     fn client_request_validate_example() {
         ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
     }
 
+    #[test]
+    fn client_request_raw_bytes() {
+        let request = ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
+
+        let idk = request.client_params.identity_key_bytes();
+        assert_eq!(
+            request.client_params.identity_key,
+            decode_public_key(&BASE64_URL_SAFE_NO_PAD.encode(idk)).unwrap()
+        );
+
+        let ids = request.identity_signature_bytes();
+        assert_eq!(
+            request.identity_signature,
+            decode_signature(&BASE64_URL_SAFE_NO_PAD.encode(ids)).unwrap()
+        );
+    }
+
+    #[test]
+    fn client_request_strict_signatures() {
+        let request = ClientRequest::from_query_string_strict(TEST_CLIENT_REQUEST).unwrap();
+        assert_eq!(
+            ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap(),
+            request
+        );
+
+        let malleated = TEST_CLIENT_REQUEST.replace(
+            &encode_signature(&request.identity_signature),
+            &malleate_signature(request.identity_signature_bytes()),
+        );
+
+        let lenient = ClientRequest::from_query_string(&malleated).unwrap();
+        assert!(lenient.verify_signatures().is_err());
+        assert!(ClientRequest::from_query_string_strict(&malleated).is_err());
+        assert!(ClientRequest::from_form_body_strict(&malleated).is_err());
+        assert_eq!(
+            request,
+            ClientRequest::from_form_body_strict(TEST_CLIENT_REQUEST).unwrap()
+        );
+    }
+
+    #[test]
+    fn client_request_strict_unlock_request_signature() {
+        let request = ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
+        let urs = request.identity_signature_bytes();
+
+        let canonical = format!(
+            "{}&urs={}",
+            TEST_CLIENT_REQUEST,
+            BASE64_URL_SAFE_NO_PAD.encode(urs)
+        );
+        assert!(ClientRequest::from_query_string_strict(&canonical).is_ok());
+
+        let malleated = format!("{}&urs={}", TEST_CLIENT_REQUEST, malleate_signature(urs));
+        let lenient = ClientRequest::from_query_string(&malleated).unwrap();
+        assert_eq!(
+            Some(malleate_signature(urs)),
+            lenient.unlock_request_signature
+        );
+        assert!(ClientRequest::from_query_string_strict(&malleated).is_err());
+        assert!(ClientRequest::from_form_body_strict(&malleated).is_err());
+
+        // Even the lenient parsers need the urs to be a signature
+        let invalid = format!("{}&urs=abcd", TEST_CLIENT_REQUEST);
+        assert!(ClientRequest::from_query_string(&invalid).is_err());
+        assert!(ClientRequest::from_form_body(&invalid).is_err());
+    }
+
+    #[test]
+    fn client_request_qrl_server_url() {
+        // Sign over the url exactly as a client that launched from a qrl://
+        // link would, even though the parsed url is normalized to sqrl://
+        let identity = test_identity();
+        let client =
+            ClientParameters::new(ClientCommand::Query, identity.verifying_key()).to_base64();
+        let server = BASE64_URL_SAFE_NO_PAD.encode("qrl://example.com/cli.sqrl?nut=abc");
+        let ids = identity.sign(format!("{}{}", client, server).as_bytes());
+        let query_string = format!(
+            "client={}&server={}&ids={}",
+            client,
+            server,
+            encode_signature(&ids)
+        );
+
+        let request = ClientRequest::from_query_string(&query_string).unwrap();
+        match &request.server_data {
+            ServerData::Url { url } => {
+                assert_eq!("sqrl://example.com/cli.sqrl?nut=abc", url.to_string())
+            }
+            ServerData::ServerResponse { .. } => panic!("Expected a url"),
+        }
+        request.verify_signatures().unwrap();
+        assert_eq!(query_string, request.to_query_string());
+    }
+
+    #[test]
+    fn client_request_round_trip_bytes() {
+        let request = ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
+        assert_eq!(TEST_CLIENT_REQUEST, request.to_query_string());
+
+        // A client may encode its parameters differently (here with bare
+        // newlines), and the signature must still cover what it sent
+        let identity = test_identity();
+        let client = BASE64_URL_SAFE_NO_PAD.encode(format!(
+            "ver=1\ncmd=query\nidk={}\n",
+            encode_public_key(&identity.verifying_key())
+        ));
+        let server = TEST_SQRL_URL;
+        let ids = encode_signature(&identity.sign(format!("{}{}", client, server).as_bytes()));
+        let query_string = format!("client={}&server={}&ids={}", client, server, ids);
+        let mut request = ClientRequest::from_query_string(&query_string).unwrap();
+        request.verify_signatures().unwrap();
+        assert_eq!(query_string, request.to_query_string());
+
+        // Changing the parameters encodes them again
+        request.client_params.button = Some(1);
+        assert_ne!(query_string, request.to_query_string());
+        assert!(request.verify_signatures().is_err());
+    }
+
+    #[test]
+    fn client_request_round_trip_normalized_server_data() {
+        // Server values the url crate or the encoder would normalize must
+        // still be signed and echoed exactly as the client sent them
+        let identity = test_identity();
+        let client =
+            ClientParameters::new(ClientCommand::Query, identity.verifying_key()).to_base64();
+        let servers = [
+            BASE64_URL_SAFE_NO_PAD.encode("SQRL://Example.com/a/../cli.sqrl?nut=abc"),
+            BASE64_URL_SAFE_NO_PAD.encode("qrl://example.com:8443/cli.sqrl?nut=abc"),
+            base64::prelude::BASE64_URL_SAFE.encode("sqrl://example.com/cli.sqrl?nut=abcde"),
+            BASE64_URL_SAFE_NO_PAD.encode("tif=5\nqry=/cli.sqrl?nut=abc\nnut=abc\nver=1\n"),
+        ];
+
+        for server in servers {
+            let ids = encode_signature(&identity.sign(format!("{}{}", client, server).as_bytes()));
+            let query_string = format!("client={}&server={}&ids={}", client, server, ids);
+            let request = ClientRequest::from_query_string(&query_string).unwrap();
+            request.verify_signatures().unwrap();
+            assert_eq!(query_string, request.to_query_string(), "{}", server);
+        }
+    }
+
+    #[test]
+    fn client_request_builder_protocol_version() {
+        let identity = test_identity();
+        let server_data = ServerData::from_base64(TEST_SQRL_URL).unwrap();
+
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data.clone())
+            .build_and_sign(&identity)
+            .unwrap();
+        assert_eq!("1", request.client_params.protocol_version.to_string());
+
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data)
+            .protocol_version(ProtocolVersion::new("1-3").unwrap())
+            .build_and_sign(&identity)
+            .unwrap();
+        assert!(request.client_params.to_string().starts_with("ver=1-3\r\n"));
+        request.verify_signatures().unwrap();
+
+        let mut params = ClientParameters::new(ClientCommand::Query, identity.verifying_key());
+        params.protocol_version = ProtocolVersion::new("1-3").unwrap();
+        let decoded = ClientParameters::from_base64(&params.to_base64()).unwrap();
+        assert_eq!(params.protocol_version, decoded.protocol_version);
+    }
+
+    #[test]
+    fn server_data_describe() {
+        let server_data = ServerData::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        let description = server_data.describe();
+        assert!(description.contains("nut=1WM9lfF1ST-z"));
+        assert!(description.contains("tif=0x5 [CurrentIdMatch, IpsMatch]"));
+        assert!(description.contains("qry=/cli.sqrl?nut=1WM9lfF1ST-z"));
+
+        let server_data = ServerData::from_base64(TEST_SQRL_URL).unwrap();
+        assert!(server_data.describe().starts_with("url sqrl://"));
+    }
+
+    #[test]
+    fn client_request_redacted() {
+        let request = ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
+        let redacted = request.redacted();
+        assert_eq!(
+            "cmd=query ver=1 opt=cps~suk idk=iggcu_… pidk=E6Qs2g… ids=*** nut=e7we6wCtoSxl",
+            redacted
+        );
+        assert!(!redacted.contains(&encode_public_key(&request.client_params.identity_key)));
+        assert!(!redacted.contains(&encode_signature(&request.identity_signature)));
+
+        let identity = test_identity();
+        let index_secret = BASE64_URL_SAFE_NO_PAD.encode([4; 32]);
+        let server_unlock_key = BASE64_URL_SAFE_NO_PAD.encode([5; 32]);
+        let verify_unlock_key = BASE64_URL_SAFE_NO_PAD.encode([6; 32]);
+        let request = ClientRequestBuilder::new(
+            ClientCommand::Ident,
+            ServerData::from_base64(TEST_SERVER_RESPONSE).unwrap(),
+        )
+        .button(1)
+        .index_secret(index_secret.clone())
+        .server_unlock_key(server_unlock_key.clone())
+        .verify_unlock_key(verify_unlock_key.clone())
+        .build_and_sign(&identity)
+        .unwrap();
+        assert_eq!(
+            request,
+            ClientRequest::from_query_string(&request.to_query_string()).unwrap()
+        );
+        let redacted = request.redacted();
+        assert!(redacted.contains(" btn=1 "));
+        assert!(redacted.contains(" ins=*** suk=*** vuk=*** ids=*** nut=1WM9lfF1ST-z"));
+        for secret in [index_secret, server_unlock_key, verify_unlock_key] {
+            assert!(!redacted.contains(&secret));
+        }
+    }
+
+    #[test]
+    fn client_request_from_form_body() {
+        let expected = ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
+
+        // Real clients may reorder fields and terminate the body with a newline
+        let mut fields: Vec<&str> = TEST_CLIENT_REQUEST.split('&').collect();
+        fields.reverse();
+        let body = format!("{}\r\n", fields.join("&"));
+        assert_eq!(expected, ClientRequest::from_form_body(&body).unwrap());
+        assert_eq!(
+            expected,
+            ClientRequest::from_form_body(&format!("{}\n", TEST_CLIENT_REQUEST)).unwrap()
+        );
+    }
+
+    #[test]
+    fn client_request_parameter_order() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let signed = signed_test_request(&identity, Some(&previous)).to_query_string();
+
+        for query_string in [TEST_CLIENT_REQUEST, signed.as_str()] {
+            let expected = ClientRequest::from_query_string(query_string).unwrap();
+            let mut fields: Vec<&str> = query_string.split('&').collect();
+
+            // Try every rotation of the fields, both forwards and reversed
+            // (ids, server, client first)
+            let mut orders = Vec::new();
+            for _ in 0..2 {
+                for _ in 0..fields.len() {
+                    fields.rotate_left(1);
+                    orders.push(fields.join("&"));
+                }
+                fields.reverse();
+            }
+            assert!(orders.iter().any(|x| x.starts_with("ids=")));
+
+            for order in orders {
+                for parsed in [
+                    ClientRequest::from_query_string(&order),
+                    ClientRequest::from_query_string_strict(&order),
+                    ClientRequest::from_form_body(&order),
+                ] {
+                    let parsed = parsed.unwrap();
+                    assert_eq!(expected, parsed, "{}", order);
+                    assert_eq!(query_string, parsed.to_query_string());
+                }
+            }
+        }
+
+        ClientRequest::from_query_string(&signed)
+            .unwrap()
+            .verify_signatures()
+            .unwrap();
+    }
+
+    #[test]
+    fn client_request_from_form_body_percent_encoded() {
+        let expected = ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
+        let body = TEST_CLIENT_REQUEST.replace("ids=hcXW", "ids=%68cXW");
+        assert_eq!(expected, ClientRequest::from_form_body(&body).unwrap());
+    }
+
+    #[test]
+    fn client_request_from_form_body_invalid() {
+        assert!(ClientRequest::from_form_body("").is_err());
+        assert!(ClientRequest::from_form_body("client\n").is_err());
+    }
+
+    fn signed_test_request(identity: &SigningKey, previous: Option<&SigningKey>) -> ClientRequest {
+        let mut params = ClientParameters::new(ClientCommand::Query, identity.verifying_key());
+        params.previous_identity_key = previous.map(|x| x.verifying_key());
+        let server_data = ServerData::from_base64(TEST_SQRL_URL).unwrap();
+        let mut request = ClientRequest::new(params, server_data, Signature::from_bytes(&[0; 64]));
+
+        let signed_string = request.get_signed_string();
+        request.identity_signature = identity.sign(signed_string.as_bytes());
+        request.previous_identity_signature = previous.map(|x| x.sign(signed_string.as_bytes()));
+        request
+    }
+
+    #[test]
+    fn client_request_clone() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let request = signed_test_request(&identity, Some(&previous));
+        let mut cloned = request.clone();
+        assert_eq!(request, cloned);
+
+        cloned.client_params.button = Some(1);
+        assert_ne!(request, cloned);
+    }
+
+    #[test]
+    fn client_request_for_retry() {
+        let identity = test_identity();
+        let request = signed_test_request(&identity, None);
+        let retry_data = ServerData::from_response(ServerResponse::new(
+            Nut::parse("newnut").unwrap(),
+            vec![TIFValue::TransientError],
+            QueryUrl::parse("/cli.sqrl?nut=newnut").unwrap(),
+        ));
+
+        let retry = request.for_retry(retry_data.clone(), &identity);
+        assert_eq!(request.client_params, retry.client_params);
+        assert_eq!(retry_data, retry.server_data);
+        assert_ne!(request.identity_signature, retry.identity_signature);
+        retry.verify_signatures().unwrap();
+    }
+
+    #[test]
+    fn client_request_verify_signatures() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        signed_test_request(&identity, None)
+            .verify_signatures()
+            .unwrap();
+        signed_test_request(&identity, Some(&previous))
+            .verify_signatures()
+            .unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn client_request_verify_signatures_async() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let request = signed_test_request(&identity, Some(&previous));
+        assert_eq!(
+            request,
+            request.clone().verify_signatures_async().await.unwrap()
+        );
+
+        let mut request = request;
+        request.identity_signature = Signature::from_bytes(&[7; 64]);
+        assert!(request.verify_signatures_async().await.is_err());
+    }
+
+    #[cfg(feature = "batch")]
+    #[test]
+    fn client_request_verify_batch() {
+        let previous = SigningKey::from_bytes(&[100; 32]);
+        let mut requests: Vec<ClientRequest> = (1..=8)
+            .map(|i| {
+                let identity = SigningKey::from_bytes(&[i; 32]);
+                signed_test_request(&identity, (i % 2 == 0).then_some(&previous))
+            })
+            .collect();
+        ClientRequest::verify_batch(&requests).unwrap();
+        ClientRequest::verify_batch(&[]).unwrap();
+
+        requests[5].identity_signature = requests[4].identity_signature;
+        match ClientRequest::verify_batch(&requests) {
+            Ok(_) => panic!("Verified a batch with a tampered request"),
+            Err(e) => {
+                assert!(e
+                    .to_string()
+                    .starts_with("Request 5 in batch failed to verify"));
+                assert_eq!(Some(TIFValue::ClientFailure), e.suggested_tif());
+            }
+        }
+
+        // A pidk without its pids also fails the batch
+        requests[5] = signed_test_request(&SigningKey::from_bytes(&[6; 32]), Some(&previous));
+        requests[3].previous_identity_signature = None;
+        match ClientRequest::verify_batch(&requests) {
+            Ok(_) => panic!("Verified a batch with a missing pids"),
+            Err(e) => assert!(e
+                .to_string()
+                .starts_with("Request 3 in batch failed to verify")),
+        }
+    }
+
+    // Sign the request's identity signature by hand with the nonce point
+    // [nonce]B + torsion, which verify_strict rejects unless torsion is the
+    // identity
+    #[cfg(feature = "batch")]
+    fn sign_with_torsion(
+        request: &mut ClientRequest,
+        identity: &SigningKey,
+        nonce: curve25519_dalek::Scalar,
+        torsion: curve25519_dalek::EdwardsPoint,
+    ) {
+        use curve25519_dalek::{EdwardsPoint, Scalar};
+        use sha2::{Digest, Sha512};
+
+        let r = (EdwardsPoint::mul_base(&nonce) + torsion).compress();
+        let hash = Sha512::new()
+            .chain_update(r.as_bytes())
+            .chain_update(identity.verifying_key().as_bytes())
+            .chain_update(request.get_signed_string().as_bytes())
+            .finalize();
+        let k = Scalar::from_bytes_mod_order_wide(&hash.as_slice().try_into().unwrap());
+        let s = nonce + k * identity.to_scalar();
+
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(r.as_bytes());
+        signature[32..].copy_from_slice(s.as_bytes());
+        request.identity_signature = Signature::from_bytes(&signature);
+    }
+
+    #[cfg(feature = "batch")]
+    #[test]
+    fn client_request_verify_batch_strict_checks() {
+        use curve25519_dalek::{constants::EIGHT_TORSION, Scalar};
+
+        let identity = test_identity();
+        let mut request = signed_test_request(&identity, None);
+
+        // The signature verifies with no torsion, so the checks below are
+        // only down to the torsion
+        sign_with_torsion(&mut request, &identity, Scalar::from(7u8), EIGHT_TORSION[0]);
+        request.verify_signatures().unwrap();
+        ClientRequest::verify_batch(&[request.clone()]).unwrap();
+
+        // A small-order R would pass some batches, so it is never batched
+        for i in 1..=32 {
+            let identity = SigningKey::from_bytes(&[i; 32]);
+            let mut request = signed_test_request(&identity, None);
+            let torsion = EIGHT_TORSION[1 + i as usize % 7];
+            sign_with_torsion(&mut request, &identity, Scalar::ZERO, torsion);
+            assert!(request.verify_signatures().is_err());
+            assert!(ClientRequest::verify_batch(&[request]).is_err());
+        }
+
+        // A non-canonical S is never batched either
+        let mut malleated = signed_test_request(&identity, None);
+        malleated.identity_signature =
+            decode_signature(&malleate_signature(malleated.identity_signature_bytes())).unwrap();
+        assert!(malleated.verify_signatures().is_err());
+        assert!(ClientRequest::verify_batch(&[malleated]).is_err());
+
+        // An R with a small-order component is always caught by
+        // verify_strict, but only sometimes by the batch, as documented on
+        // verify_batch
+        let accepted = (1..=64u8)
+            .filter(|&nonce| {
+                sign_with_torsion(
+                    &mut request,
+                    &identity,
+                    Scalar::from(nonce),
+                    EIGHT_TORSION[1],
+                );
+                assert!(request.verify_signatures().is_err());
+                ClientRequest::verify_batch(&[request.clone()]).is_ok()
+            })
+            .count();
+        assert!(accepted > 0 && accepted < 64);
+    }
+
+    #[test]
+    fn client_request_verify_bad_identity_signature() {
+        let identity = test_identity();
+        let mut request = signed_test_request(&identity, None);
+        request.identity_signature = Signature::from_bytes(&[7; 64]);
+        assert!(request.verify_signatures().is_err());
+    }
+
+    #[test]
+    fn client_request_verify_garbage_previous_identity_signature() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let mut request = signed_test_request(&identity, Some(&previous));
+        request.previous_identity_signature = Some(Signature::from_bytes(&[7; 64]));
+        assert!(request.verify_signatures().is_err());
+
+        // A pids signed by the wrong key must not prove the previous identity
+        let signed_string = request.get_signed_string();
+        request.previous_identity_signature = Some(identity.sign(signed_string.as_bytes()));
+        assert!(request.verify_signatures().is_err());
+
+        request.previous_identity_signature = None;
+        assert!(request.verify_signatures().is_err());
+    }
+
+    #[test]
+    fn client_request_verify_and_validate() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let mut request = signed_test_request(&identity, Some(&previous));
+        request.verify_and_validate().unwrap();
+
+        // Structural errors are reported before any signature is verified
+        request.previous_identity_signature = None;
+        request.identity_signature = Signature::from_bytes(&[7; 64]);
+        let error = request.verify_and_validate().unwrap_err();
+        assert!(error.to_string().contains("no previous identity signature"));
+    }
+
+    #[test]
+    fn client_request_query_with_previous_identity() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let server_data = ServerData::from_base64(TEST_SQRL_URL).unwrap();
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data)
+            .previous_identity(previous.clone())
+            .build_and_sign(&identity)
+            .unwrap();
+
+        let decoded = ClientRequest::from_query_string(&request.to_query_string()).unwrap();
+        decoded.verify_and_validate().unwrap();
+        assert_eq!(ClientCommand::Query, *decoded.command());
+        assert_eq!(
+            Some(previous.verifying_key()),
+            decoded.client_params.previous_identity_key
+        );
+        assert!(decoded.previous_identity_signature.is_some());
+
+        // The server only recognizes the previous identity
+        let response = ServerResponse::new(
+            Nut::parse("nextnut").unwrap(),
+            vec![TIFValue::PreviousIdMatch, TIFValue::IpsMatch],
+            QueryUrl::parse("/cli.sqrl?nut=nextnut").unwrap(),
+        );
+        let response = ServerResponse::from_base64(&response.to_base64()).unwrap();
+        assert_eq!(IdentityState::KnownViaPrevious, response.identity_state());
+    }
+
+    #[test]
+    fn client_request_errors_suggest_tif() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+
+        let mut request = signed_test_request(&identity, None);
+        request.identity_signature = Signature::from_bytes(&[7; 64]);
+        let error = request.verify_and_validate().unwrap_err();
+        assert_eq!(Some(TIFValue::ClientFailure), error.suggested_tif());
+
+        let mut request = signed_test_request(&identity, Some(&previous));
+        request.previous_identity_signature = None;
+        let error = request.validate().unwrap_err();
+        assert_eq!(Some(TIFValue::ClientFailure), error.suggested_tif());
+
+        let request = signed_test_request(&identity, Some(&identity));
+        let error = request.validate().unwrap_err();
+        assert_eq!(Some(TIFValue::BadId), error.suggested_tif());
+
+        let mut request = signed_test_request(&identity, None);
+        request.client_params.button = Some(3);
+        let error = request.validate().unwrap_err();
+        assert_eq!(Some(TIFValue::ClientFailure), error.suggested_tif());
+
+        // Errors without a specific cause don't suggest a flag
+        let error = ClientRequest::from_query_string("client=abc").unwrap_err();
+        assert_eq!(None, error.suggested_tif());
+    }
+
+    #[test]
+    fn client_request_validate_same_identity_keys() {
+        let identity = test_identity();
+        let request = signed_test_request(&identity, Some(&identity));
+        let error = request.validate().unwrap_err();
+        assert!(error.to_string().contains("must be different"));
+    }
+
+    #[test]
+    fn client_request_wrong_length_ids_fails_decode() {
+        let identity = test_identity();
+        let request = signed_test_request(&identity, None);
+        let query_string = request.to_query_string();
+        let (query_string, _) = query_string.split_once("&ids=").unwrap();
+        let query_string = format!(
+            "{}&ids={}",
+            query_string,
+            BASE64_URL_SAFE_NO_PAD.encode([7; 63])
+        );
+
+        match ClientRequest::from_query_string(&query_string) {
+            Ok(_) => panic!("Parsed a request with a 63 byte signature"),
+            Err(e) => assert!(e.to_string().contains("Expected 64 bytes")),
+        }
+    }
+
+    #[test]
+    fn client_request_builder_ident() {
+        let identity = test_identity();
+        let server_data = ServerData::from_response(test_response(&[TIFValue::CurrentIdMatch]));
+
+        let request = ClientRequestBuilder::new(ClientCommand::Ident, server_data)
+            .build_and_sign(&identity)
+            .unwrap();
+        assert_eq!(request.client_params.command, ClientCommand::Ident);
+        request.validate().unwrap();
+        request.verify_signatures().unwrap();
+    }
+
+    #[test]
+    fn client_request_builder_enable_missing_urs() {
+        let identity = test_identity();
+        let server_data = ServerData::from_response(test_response(&[
+            TIFValue::CurrentIdMatch,
+            TIFValue::SqrlDisabled,
+        ]));
+
+        let builder = ClientRequestBuilder::new(ClientCommand::Enable, server_data);
+        assert!(builder.validate().is_err());
+        let builder = builder.unlock_request_key(SigningKey::from_bytes(&[3; 32]));
+        let request = builder.build_and_sign(&identity).unwrap();
+        assert!(request.unlock_request_signature.is_some());
+        request.validate().unwrap();
+
+        let server_data = ServerData::from_base64(TEST_SQRL_URL).unwrap();
+        let result =
+            ClientRequestBuilder::new(ClientCommand::Remove, server_data).build_and_sign(&identity);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn client_request_new_query() {
+        let identity = test_identity();
+        let url = SqrlUrl::parse("sqrl://example.com/cli.sqrl?nut=1WM9lfF1ST-z").unwrap();
+        let request = ClientRequest::new_query(&url, &identity);
+        request.verify_and_validate().unwrap();
+        assert_eq!(ClientCommand::Query, *request.command());
+        assert_eq!(ServerData::from(url.clone()), request.server_data);
+
+        let built = ClientRequestBuilder::new(ClientCommand::Query, ServerData::from(url))
+            .build_and_sign(&identity)
+            .unwrap();
+        assert_eq!(built, request);
+    }
+
+    #[test]
+    fn client_request_sign_with_unlock() {
+        let identity = test_identity();
+        let unlock_key = SigningKey::from_bytes(&[3; 32]);
+        let server_data = ServerData::from_response(test_response(&[TIFValue::CurrentIdMatch]));
+        let params = ClientParameters::new(ClientCommand::Remove, identity.verifying_key());
+
+        let request = ClientRequest::sign_with_unlock(
+            params.clone(),
+            server_data.clone(),
+            &identity,
+            &unlock_key,
+        )
+        .unwrap();
+        request.validate().unwrap();
+        request.verify_signatures().unwrap();
+
+        let urs = decode_signature(request.unlock_request_signature.as_ref().unwrap()).unwrap();
+        unlock_key
+            .verifying_key()
+            .verify_strict(request.get_signed_string().as_bytes(), &urs)
+            .unwrap();
+
+        // The identity signing must match the idk in the parameters
+        let other = SigningKey::from_bytes(&[2; 32]);
+        assert!(ClientRequest::sign_with_unlock(
+            params.clone(),
+            server_data.clone(),
+            &other,
+            &unlock_key
+        )
+        .is_err());
+
+        // A pidk can't be sent without the pids to go with it
+        let mut params = params;
+        params.previous_identity_key = Some(other.verifying_key());
+        assert!(
+            ClientRequest::sign_with_unlock(params, server_data, &identity, &unlock_key).is_err()
+        );
+    }
+
+    #[test]
+    fn client_request_verify_unlock_signature() {
+        let identity = test_identity();
+        let unlock_key = SigningKey::from_bytes(&[3; 32]);
+        let server_data = ServerData::from_response(test_response(&[TIFValue::CurrentIdMatch]));
+        let params = ClientParameters::new(ClientCommand::Enable, identity.verifying_key());
+        let request =
+            ClientRequest::sign_with_unlock(params, server_data, &identity, &unlock_key).unwrap();
+
+        request
+            .verify_unlock_signature(&unlock_key.verifying_key())
+            .unwrap();
+        assert!(request
+            .verify_unlock_signature(&SigningKey::from_bytes(&[4; 32]).verifying_key())
+            .is_err());
+
+        let mut request = request;
+        request.unlock_request_signature = None;
+        assert!(request
+            .verify_unlock_signature(&unlock_key.verifying_key())
+            .is_err());
+    }
+
+    #[test]
+    fn client_request_sign_with_previous_identity() {
+        let identity = test_identity();
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let server_data = ServerData::from_base64(TEST_SQRL_URL).unwrap();
+        let params = ClientParameters::new(ClientCommand::Query, identity.verifying_key());
+
+        let request = ClientRequest::sign_with_previous_identity(
+            params.clone(),
+            server_data.clone(),
+            &identity,
+            &previous,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(previous.verifying_key()),
+            request.client_params.previous_identity_key
+        );
+        assert!(request.previous_identity_signature.is_some());
+        request.verify_and_validate().unwrap();
+
+        // The builder also derives the pidk and pids from the same key
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data.clone())
+            .previous_identity(previous.clone())
+            .build_and_sign(&identity)
+            .unwrap();
+        assert!(request.client_params.previous_identity_key.is_some());
+        assert!(request.previous_identity_signature.is_some());
+        request.verify_and_validate().unwrap();
+
+        assert!(ClientRequest::sign_with_previous_identity(
+            params,
+            server_data,
+            &previous,
+            &previous
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn client_request_builder_new_identity_requires_unlock_keys() {
+        let identity = test_identity();
+        let new_response = || ServerData::from_response(test_response(&[TIFValue::IpsMatch]));
+
+        assert!(
+            ClientRequestBuilder::new(ClientCommand::Ident, new_response())
+                .build_and_sign(&identity)
+                .is_err()
+        );
+        let request = ClientRequestBuilder::new(ClientCommand::Ident, new_response())
+            .server_unlock_key(BASE64_URL_SAFE_NO_PAD.encode([5; 32]))
+            .verify_unlock_key(BASE64_URL_SAFE_NO_PAD.encode([6; 32]))
+            .build_and_sign(&identity)
+            .unwrap();
+        request.validate().unwrap();
+
+        // Keys the server couldn't parse are rejected before signing
+        let error = ClientRequestBuilder::new(ClientCommand::Ident, new_response())
+            .server_unlock_key("suk".to_owned())
+            .verify_unlock_key(BASE64_URL_SAFE_NO_PAD.encode([6; 32]))
+            .build_and_sign(&identity)
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Invalid suk value"));
+        assert!(
+            ClientRequestBuilder::new(ClientCommand::Query, new_response())
+                .index_secret(BASE64_URL_SAFE_NO_PAD.encode([4; 16]))
+                .validate()
+                .is_err()
+        );
+
+        // The builder and request validation apply the same rule: a query
+        // for an unknown identity doesn't need the unlock keys, an ident does
+        let request = ClientRequestBuilder::new(ClientCommand::Query, new_response())
+            .build_and_sign(&identity)
+            .unwrap();
+        request.validate().unwrap();
+
+        let mut request = request;
+        request.client_params.command = ClientCommand::Ident;
+        assert!(request.validate().is_err());
+    }
+
+    #[test]
+    fn client_parameters_validate_button() {
+        let mut params = ClientParameters::new(
+            ClientCommand::Query,
+            decode_public_key("iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w").unwrap(),
+        );
+        params.validate().unwrap();
+        params.button = Some(1);
+        params.validate().unwrap();
+        params.button = Some(9);
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn client_request_validate_button_against_ask() {
+        let identity = test_identity();
+        let mut response = test_response(&[TIFValue::CurrentIdMatch]);
+        response.ask = Some("Q29udGludWU~T0s".to_owned());
+        let server_data = ServerData::from_response(response);
+
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data.clone())
+            .button(1)
+            .build_and_sign(&identity)
+            .unwrap();
+        request.validate().unwrap();
+
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data)
+            .button(2)
+            .build_and_sign(&identity)
+            .unwrap();
+        assert!(request.validate().is_err());
+
+        // A malformed ask is an error rather than a button count
+        let mut response = test_response(&[TIFValue::CurrentIdMatch]);
+        response.ask = Some("Q29udGludWU~T0s~WWVz~Tm8".to_owned());
+        let request =
+            ClientRequestBuilder::new(ClientCommand::Query, ServerData::from_response(response))
+                .button(1)
+                .build_and_sign(&identity)
+                .unwrap();
+        let error = request.validate().unwrap_err();
+        assert!(error.to_string().contains("Invalid ask"));
+        assert_eq!(Some(TIFValue::ClientFailure), error.suggested_tif());
+    }
+
     #[test]
     fn client_parameters_encode_decode() {
         let mut params = ClientParameters::new(
@@ -561,6 +2511,233 @@ mod tests {
         assert_eq!(params, decoded);
     }
 
+    #[test]
+    fn option_policy_merge() {
+        let requested = [ClientOption::Hardlock, ClientOption::ClientProvidedSession];
+
+        // No policy leaves the client's options as requested
+        assert_eq!(
+            vec![ClientOption::Hardlock, ClientOption::ClientProvidedSession],
+            OptionPolicy::default().merge(&requested)
+        );
+
+        let policy = OptionPolicy {
+            required: vec![ClientOption::SQRLOnly, ClientOption::NoIPTest],
+            refused: vec![ClientOption::Hardlock, ClientOption::NoIPTest],
+        };
+        assert_eq!(
+            vec![
+                ClientOption::NoIPTest,
+                ClientOption::SQRLOnly,
+                ClientOption::ClientProvidedSession
+            ],
+            policy.merge(&requested)
+        );
+        assert_eq!(
+            vec![ClientOption::NoIPTest, ClientOption::SQRLOnly],
+            policy.merge(&[])
+        );
+        assert_eq!(
+            vec![ClientOption::NoIPTest, ClientOption::SQRLOnly],
+            policy.merge(&[ClientOption::SQRLOnly, ClientOption::Hardlock])
+        );
+    }
+
+    #[test]
+    fn client_parameters_account_flags() {
+        let identity_key = test_identity().verifying_key();
+        let mut params = ClientParameters::new(ClientCommand::Ident, identity_key);
+        assert_eq!(AccountFlags::default(), params.account_flags());
+
+        params.options = Some(vec![
+            ClientOption::ClientProvidedSession,
+            ClientOption::Hardlock,
+        ]);
+        assert_eq!(
+            AccountFlags {
+                sqrl_only: false,
+                hardlock: true,
+            },
+            params.account_flags()
+        );
+
+        let params = ClientParameters::from_str(&format!(
+            "ver=1\r\ncmd=ident\r\nidk={}\r\nopt=sqrlonly~hardlock\r\n",
+            encode_public_key(&identity_key)
+        ))
+        .unwrap();
+        assert_eq!(
+            AccountFlags {
+                sqrl_only: true,
+                hardlock: true,
+            },
+            params.account_flags()
+        );
+    }
+
+    #[test]
+    fn client_parameters_empty_options() {
+        let identity_key = test_identity().verifying_key();
+        let mut params = ClientParameters::new(ClientCommand::Query, identity_key);
+        params.options = Some(vec![]);
+        assert!(!params.to_string().contains(OPTIONS_KEY));
+
+        let decoded = ClientParameters::from_base64(&params.to_base64()).unwrap();
+        assert_eq!(None, decoded.options);
+
+        let decoded = ClientParameters::from_str(&format!(
+            "ver=1\r\ncmd=query\r\nidk={}\r\nopt=\r\n",
+            encode_public_key(&identity_key)
+        ))
+        .unwrap();
+        assert_eq!(None, decoded.options);
+
+        // Unknown options are still encoded on their own
+        params.unknown_options = vec!["future".to_owned()];
+        assert!(params.to_string().contains("opt=future"));
+    }
+
+    #[test]
+    fn client_parameters_has_option() {
+        let mut params = ClientParameters::new(
+            ClientCommand::Query,
+            decode_public_key("iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w").unwrap(),
+        );
+        assert!(!params.has_option(ClientOption::ClientProvidedSession));
+        assert!(!params.wants_cps());
+
+        params.options = Some(vec![]);
+        assert!(!params.wants_cps());
+
+        params.options = Some(vec![
+            ClientOption::ClientProvidedSession,
+            ClientOption::SQRLOnly,
+        ]);
+        assert!(params.has_option(ClientOption::ClientProvidedSession));
+        assert!(params.wants_cps());
+        assert!(params.sqrl_only());
+        assert!(!params.hardlock());
+        assert!(!params.has_option(ClientOption::ServerUnlockKey));
+    }
+
+    #[test]
+    fn client_parameters_sqrl_only() {
+        let params = ClientParameters::from_str(
+            "ver=1\r\ncmd=ident\r\nidk=iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w\r\nopt=cps~sqrlonly\r\n",
+        )
+        .unwrap();
+        assert!(params.sqrl_only());
+        assert!(params.account_flags().sqrl_only);
+
+        let params = ClientParameters::from_str(
+            "ver=1\r\ncmd=ident\r\nidk=iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w\r\nopt=cps~hardlock\r\n",
+        )
+        .unwrap();
+        assert!(!params.sqrl_only());
+        assert!(!params.account_flags().sqrl_only);
+
+        let params = ClientParameters::from_str(
+            "ver=1\r\ncmd=ident\r\nidk=iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w\r\n",
+        )
+        .unwrap();
+        assert!(!params.sqrl_only());
+    }
+
+    #[test]
+    fn client_parameters_encode_optional_fields() {
+        let identity_key =
+            decode_public_key("iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w").unwrap();
+        let previous_identity_key =
+            decode_public_key("E6Qs2gX7W-Pwi9Y3KAmbkuYjLSWXCtKyBcymWloHAuo").unwrap();
+        let optional_keys = [
+            OPTIONS_KEY,
+            BUTTON_KEY,
+            PREVIOUS_IDENTITY_KEY_KEY,
+            INDEX_SECRET_KEY,
+            PREVIOUS_INDEX_SECRET_KEY,
+            SERVER_UNLOCK_KEY_KEY,
+            VERIFY_UNLOCK_KEY_KEY,
+        ];
+
+        // Try every combination of optional fields being present or absent
+        for mask in 0..(1 << optional_keys.len()) {
+            let present = |i: usize| mask & (1 << i) != 0;
+            let mut params = ClientParameters::new(ClientCommand::Query, identity_key);
+            if present(0) {
+                params.options = Some(vec![ClientOption::ClientProvidedSession]);
+            }
+            if present(1) {
+                params.button = Some(1);
+            }
+            if present(2) {
+                params.previous_identity_key = Some(previous_identity_key);
+            }
+            if present(3) {
+                params.index_secret = Some(BASE64_URL_SAFE_NO_PAD.encode([3; 32]).into());
+            }
+            if present(4) {
+                params.previous_index_secret = Some(BASE64_URL_SAFE_NO_PAD.encode([4; 32]).into());
+            }
+            if present(5) {
+                params.server_unlock_key = Some(BASE64_URL_SAFE_NO_PAD.encode([5; 32]));
+            }
+            if present(6) {
+                params.verify_unlock_key = Some(BASE64_URL_SAFE_NO_PAD.encode([6; 32]));
+            }
+
+            let mut expected = vec![PROTOCOL_VERSION_KEY, COMMAND_KEY, IDENTITY_KEY_KEY];
+            for (i, key) in optional_keys.iter().enumerate() {
+                if present(i) {
+                    expected.push(key);
+                }
+            }
+
+            let encoded = params.to_string();
+            let keys: Vec<&str> = encoded
+                .split('\n')
+                .filter(|x| !x.is_empty())
+                .map(|x| x.split_once('=').unwrap().0)
+                .collect();
+            assert_eq!(expected, keys);
+            assert_eq!(encoded, params.to_string());
+            assert_eq!(params, ClientParameters::from_str(&encoded).unwrap());
+        }
+    }
+
+    #[test]
+    fn client_parameters_validate_base64_fields() {
+        let params = format!(
+            "ver=1\r\ncmd=ident\r\nidk={}\r\n",
+            encode_public_key(&test_identity().verifying_key())
+        );
+        let suk = "BMFDm7bPlsQojuJsoDTvlS1McnwgScvktF84vLjscGk";
+        let decoded = ClientParameters::from_str(&format!("{}suk={}\r\n", params, suk)).unwrap();
+        assert_eq!(Some(suk), decoded.server_unlock_key.as_deref());
+
+        for key in ["suk", "vuk", "ins", "pins"] {
+            for value in ["not+base64!", "BMFDm7bPlsQojuJsoDTvlS1Mcnw"] {
+                match ClientParameters::from_str(&format!("{}{}={}\r\n", params, key, value)) {
+                    Ok(_) => panic!("Parsed a malformed {} {}", key, value),
+                    Err(e) => assert_eq!(
+                        format!(
+                            "Invalid {} value, expected 32 base64url encoded bytes: {}",
+                            key, value
+                        ),
+                        e.to_string()
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn client_parameters_malformed_line() {
+        match ClientParameters::from_str("ver=1\r\ncmd=query\r\noops") {
+            Ok(_) => panic!("Parsed malformed client parameters"),
+            Err(e) => assert_eq!("Invalid client parameters: line 3 'oops'", e.to_string()),
+        }
+    }
+
     #[test]
     fn client_parameters_decode_example() {
         let client_parameters = ClientParameters::from_base64(TEST_CLIENT_PARAMS).unwrap();
@@ -590,6 +2767,226 @@ mod tests {
         }
     }
 
+    #[test]
+    fn client_command_from_str() {
+        for command in [
+            ClientCommand::Query,
+            ClientCommand::Ident,
+            ClientCommand::Disable,
+            ClientCommand::Enable,
+            ClientCommand::Remove,
+        ] {
+            assert_eq!(
+                command,
+                ClientCommand::from_str(&command.to_string()).unwrap()
+            );
+        }
+        assert!(ClientCommand::from_str("Query").is_err());
+        assert!(ClientCommand::from_str("").is_err());
+
+        // An unknown command is rejected rather than panicking
+        let identity = test_identity().verifying_key();
+        let params = format!(
+            "ver=1\r\ncmd=foo\r\nidk={}\r\n",
+            encode_public_key(&identity)
+        );
+        match ClientParameters::from_str(&params) {
+            Ok(_) => panic!("Parsed an unknown command"),
+            Err(e) => assert_eq!(Some(TIFValue::ClientFailure), e.suggested_tif()),
+        }
+    }
+
+    #[test]
+    fn client_option_from_str() {
+        for option in [
+            ClientOption::NoIPTest,
+            ClientOption::SQRLOnly,
+            ClientOption::Hardlock,
+            ClientOption::ClientProvidedSession,
+            ClientOption::ServerUnlockKey,
+        ] {
+            assert_eq!(option, ClientOption::from_str(&option.to_string()).unwrap());
+        }
+        assert!(ClientOption::from_str("garbage").is_err());
+    }
+
+    #[test]
+    fn client_command_requires_prior_query() {
+        assert!(!ClientCommand::Query.requires_prior_query());
+        assert!(ClientCommand::Ident.requires_prior_query());
+        assert!(ClientCommand::Disable.requires_prior_query());
+        assert!(ClientCommand::Enable.requires_prior_query());
+        assert!(ClientCommand::Remove.requires_prior_query());
+    }
+
+    #[test]
+    fn client_option_dedup_and_order() {
+        let (first, _) = ClientOption::from_option_string("suk~cps");
+        let (second, _) = ClientOption::from_option_string("cps~suk~cps");
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            vec![
+                ClientOption::ClientProvidedSession,
+                ClientOption::ServerUnlockKey
+            ]
+        );
+        assert_eq!(
+            ClientOption::to_option_string(&first, &[]),
+            ClientOption::to_option_string(&second, &[])
+        );
+        assert_eq!(
+            "cps~suk",
+            ClientOption::to_option_string(
+                &[
+                    ClientOption::ServerUnlockKey,
+                    ClientOption::ClientProvidedSession,
+                    ClientOption::ServerUnlockKey,
+                ],
+                &[]
+            )
+        );
+    }
+
+    #[test]
+    fn client_option_unknown_options() {
+        let (options, unknown_options) = ClientOption::from_option_string("cps~futurething~suk");
+        assert_eq!(
+            vec![
+                ClientOption::ClientProvidedSession,
+                ClientOption::ServerUnlockKey
+            ],
+            options
+        );
+        assert_eq!(vec!["futurething".to_owned()], unknown_options);
+
+        let params = ClientParameters::from_str(
+            "ver=1\ncmd=query\nidk=iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w\nopt=cps~futurething~suk",
+        )
+        .unwrap();
+        assert_eq!(Some(options), params.options);
+        assert_eq!(unknown_options, params.unknown_options);
+        assert!(params.to_string().contains("\nopt=cps~suk~futurething"));
+        assert_eq!(
+            params,
+            ClientParameters::from_str(&params.to_string()).unwrap()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn client_parameters_serde_json() {
+        let mut params = ClientParameters::new(
+            ClientCommand::Ident,
+            decode_public_key("iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w").unwrap(),
+        );
+        params.previous_identity_key =
+            Some(decode_public_key("E6Qs2gX7W-Pwi9Y3KAmbkuYjLSWXCtKyBcymWloHAuo").unwrap());
+        params.options = Some(vec![
+            ClientOption::NoIPTest,
+            ClientOption::ClientProvidedSession,
+        ]);
+
+        let json = serde_json::to_string(&params).unwrap();
+        assert!(json.contains("\"command\":\"ident\""));
+        assert!(json.contains("\"identity_key\":\"iggcu_e-tWq3sogaa2aADCsxRZED9on9H716TAyPR0w\""));
+        assert!(json.contains("\"options\":[\"noiptest\",\"cps\"]"));
+        let decoded: ClientParameters = serde_json::from_str(&json).unwrap();
+        assert_eq!(params, decoded);
+    }
+
+    #[test]
+    fn server_data_from_response() {
+        let response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        let encoded = response.to_base64();
+        let server_data = ServerData::from_response(response);
+        assert_eq!(encoded, server_data.to_base64());
+        assert_eq!(encoded, server_data.to_string());
+
+        let identity = test_identity();
+        let params = ClientParameters::new(ClientCommand::Ident, identity.verifying_key());
+        let signed_string = format!("{}{}", params.to_base64(), encoded);
+        let request =
+            ClientRequest::new(params, server_data, identity.sign(signed_string.as_bytes()));
+        assert_eq!(signed_string, request.get_signed_string());
+        request.verify_signatures().unwrap();
+
+        let decoded = ClientRequest::from_query_string(&request.to_query_string()).unwrap();
+        assert_eq!(request.server_data, decoded.server_data);
+    }
+
+    #[test]
+    fn server_data_matches_response() {
+        let response = test_response(&[TIFValue::CurrentIdMatch]);
+        let echoed = ServerData::from_base64(&response.to_base64()).unwrap();
+        assert!(echoed.matches_response(&response));
+
+        let mut tampered = response.clone();
+        tampered.transaction_indication_flags = vec![TIFValue::CurrentIdMatch, TIFValue::IpsMatch];
+        let echoed = ServerData::from_base64(&tampered.to_base64()).unwrap();
+        assert!(!echoed.matches_response(&response));
+
+        let echoed = ServerData::from_base64(TEST_SQRL_URL).unwrap();
+        assert!(!echoed.matches_response(&response));
+    }
+
+    #[test]
+    fn server_data_from_sqrl_url() {
+        let url = SqrlUrl::parse("sqrl://sqrl.grc.com/cli.sqrl?nut=1WM9lfF1ST-z").unwrap();
+        let encoded = BASE64_URL_SAFE_NO_PAD.encode(url.to_string());
+        let server_data = ServerData::from(url);
+        assert_eq!(encoded, server_data.to_base64());
+
+        let identity = test_identity();
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data)
+            .build_and_sign(&identity)
+            .unwrap();
+        request.verify_and_validate().unwrap();
+
+        let decoded = ClientRequest::from_query_string(&request.to_query_string()).unwrap();
+        assert_eq!(request.server_data, decoded.server_data);
+    }
+
+    #[test]
+    fn server_data_nut() {
+        let identity = test_identity();
+        let url = SqrlUrl::parse("sqrl://sqrl.grc.com/cli.sqrl?nut=1WM9lfF1ST-z").unwrap();
+        let request = ClientRequestBuilder::new(ClientCommand::Query, ServerData::from(url))
+            .build_and_sign(&identity)
+            .unwrap();
+        assert_eq!(
+            Some(Nut::parse("1WM9lfF1ST-z").unwrap()),
+            request.server_nut()
+        );
+
+        let server_data = ServerData::from_response(ServerResponse::new(
+            Nut::parse("nextnut").unwrap(),
+            vec![TIFValue::CurrentIdMatch],
+            QueryUrl::parse("/cli.sqrl?nut=nextnut").unwrap(),
+        ));
+        let request = ClientRequestBuilder::new(ClientCommand::Ident, server_data)
+            .build_and_sign(&identity)
+            .unwrap();
+        let decoded = ClientRequest::from_query_string(&request.to_query_string()).unwrap();
+        assert_eq!(Some(Nut::parse("nextnut").unwrap()), decoded.server_nut());
+
+        let url = SqrlUrl::parse("sqrl://sqrl.grc.com/cli.sqrl").unwrap();
+        assert_eq!(None, ServerData::from(url).nut());
+    }
+
+    #[test]
+    fn client_request_auth_domain() {
+        let request = ClientRequest::from_query_string(TEST_CLIENT_REQUEST).unwrap();
+        assert_eq!(Some("sqrl.steve.com".to_owned()), request.auth_domain());
+
+        let identity = test_identity();
+        let server_data = ServerData::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        let request = ClientRequestBuilder::new(ClientCommand::Query, server_data)
+            .build_and_sign(&identity)
+            .unwrap();
+        assert_eq!(None, request.auth_domain());
+    }
+
     #[test]
     fn server_data_parse_sqrl_url() {
         let data = ServerData::from_base64(TEST_SQRL_URL).unwrap();
@@ -619,7 +3016,7 @@ mod tests {
                 original_response,
                 ..
             } => {
-                assert_eq!(server_response.nut, "1WM9lfF1ST-z");
+                assert_eq!(server_response.nut.as_str(), "1WM9lfF1ST-z");
                 assert_eq!(original_response, TEST_SERVER_RESPONSE);
             }
         };
@@ -1,10 +1,13 @@
 //! A common error used by SQRL clients and servers
 
-use std::{fmt, num::ParseIntError, string::FromUtf8Error};
+use crate::server_response::TIFValue;
+use alloc::string::{FromUtf8Error, String, ToString};
+use core::{fmt, num::ParseIntError};
 
 /// An error that can occur during SQRL protocol
 pub struct SqrlError {
     error_message: String,
+    suggested_tif: Option<TIFValue>,
 }
 
 impl SqrlError {
@@ -12,8 +15,36 @@ impl SqrlError {
     pub fn new(error: String) -> Self {
         SqrlError {
             error_message: error,
+            suggested_tif: None,
         }
     }
+
+    /// Attach the tif flag a server should respond with if this error causes
+    /// it to reject a client request
+    pub fn with_suggested_tif(mut self, tif: TIFValue) -> Self {
+        self.suggested_tif = Some(tif);
+        self
+    }
+
+    /// Get the tif flag a server should respond with if this error causes it
+    /// to reject a client request, in addition to CommandFailed. Errors
+    /// without a more specific cause return `None`.
+    /// ```rust
+    /// use sqrl_protocol::{
+    ///     error::SqrlError,
+    ///     server_response::{QueryUrl, ServerResponse, TIFValue},
+    ///     Nut,
+    /// };
+    ///
+    /// let error = SqrlError::new("Bad request".to_owned()).with_suggested_tif(TIFValue::ClientFailure);
+    /// let flags: Vec<TIFValue> = error.suggested_tif().into_iter().collect();
+    /// let qry = QueryUrl::parse("/cli.sqrl").unwrap();
+    /// let response = ServerResponse::error(Nut::parse("1WM9lfF1ST-z").unwrap(), qry, &flags);
+    /// assert_eq!(0xC0, response.tif_u16());
+    /// ```
+    pub fn suggested_tif(&self) -> Option<TIFValue> {
+        self.suggested_tif
+    }
 }
 
 impl fmt::Display for SqrlError {
@@ -28,7 +59,7 @@ impl fmt::Debug for SqrlError {
     }
 }
 
-impl std::error::Error for SqrlError {}
+impl core::error::Error for SqrlError {}
 
 impl From<url::ParseError> for SqrlError {
     fn from(error: url::ParseError) -> Self {
@@ -53,3 +84,17 @@ impl From<ParseIntError> for SqrlError {
         SqrlError::new(value.to_string())
     }
 }
+
+#[cfg(feature = "getrandom")]
+impl From<getrandom::Error> for SqrlError {
+    fn from(value: getrandom::Error) -> Self {
+        SqrlError::new(value.to_string())
+    }
+}
+
+#[cfg(feature = "qr")]
+impl From<qrcode::types::QrError> for SqrlError {
+    fn from(value: qrcode::types::QrError) -> Self {
+        SqrlError::new(value.to_string())
+    }
+}
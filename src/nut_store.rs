@@ -0,0 +1,110 @@
+//! Code for a server to track the nuts it has issued
+
+use crate::{Nut, Result};
+#[cfg(feature = "std")]
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Storage for the nuts a server has issued, so each request can be tied to
+/// a nut the server handed out and a nut can't be replayed
+pub trait NutStore {
+    /// Generate and remember a new nut to send to a client
+    fn issue(&self) -> Result<Nut>;
+
+    /// Consume a nut echoed back by a client. Returns `true` only if the nut
+    /// was issued by this store, hasn't expired, and hasn't already been
+    /// consumed.
+    fn consume(&self, nut: &Nut) -> bool;
+}
+
+/// An in-memory [`NutStore`] where each nut is valid for a fixed time after
+/// being issued
+/// ```rust
+/// use sqrl_protocol::nut_store::{MemoryNutStore, NutStore};
+/// use std::time::Duration;
+///
+/// let store = MemoryNutStore::new(Duration::from_secs(300));
+/// let nut = store.issue().unwrap();
+/// assert!(store.consume(&nut));
+/// assert!(!store.consume(&nut));
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct MemoryNutStore {
+    time_to_live: Duration,
+    nuts: Mutex<HashMap<Nut, Instant>>,
+}
+
+#[cfg(feature = "std")]
+impl MemoryNutStore {
+    /// Create an empty store where nuts expire `time_to_live` after they are
+    /// issued
+    pub fn new(time_to_live: Duration) -> Self {
+        MemoryNutStore {
+            time_to_live,
+            nuts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl NutStore for MemoryNutStore {
+    fn issue(&self) -> Result<Nut> {
+        let nut = Nut::generate()?;
+        let mut nuts = self.nuts.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Drop expired nuts so the store doesn't grow without bound
+        nuts.retain(|_, issued| issued.elapsed() < self.time_to_live);
+        nuts.insert(nut.clone(), Instant::now());
+        Ok(nut)
+    }
+
+    fn consume(&self, nut: &Nut) -> bool {
+        let mut nuts = self.nuts.lock().unwrap_or_else(|e| e.into_inner());
+        match nuts.remove(nut) {
+            Some(issued) => issued.elapsed() < self.time_to_live,
+            None => false,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_nut_store_issue_consume() {
+        let store = MemoryNutStore::new(Duration::from_secs(300));
+        let first = store.issue().unwrap();
+        let second = store.issue().unwrap();
+        assert_ne!(first, second);
+
+        assert!(store.consume(&second));
+        assert!(store.consume(&first));
+    }
+
+    #[test]
+    fn memory_nut_store_reject_replay() {
+        let store = MemoryNutStore::new(Duration::from_secs(300));
+        let nut = store.issue().unwrap();
+        assert!(store.consume(&nut));
+        assert!(!store.consume(&nut));
+    }
+
+    #[test]
+    fn memory_nut_store_reject_unknown() {
+        let store = MemoryNutStore::new(Duration::from_secs(300));
+        store.issue().unwrap();
+        assert!(!store.consume(&Nut::parse("1WM9lfF1ST-z").unwrap()));
+    }
+
+    #[test]
+    fn memory_nut_store_reject_expired() {
+        let store = MemoryNutStore::new(Duration::ZERO);
+        let nut = store.issue().unwrap();
+        assert!(!store.consume(&nut));
+    }
+}
@@ -1,34 +1,68 @@
 //! Code for a server to respond to client requests
 
 use super::{
-    encode_newline_data, get_or_error, parse_newline_data, ProtocolVersion, PROTOCOL_VERSIONS,
+    decode_base64, decode_base64_payload, encode_newline_data, get_base64_field, get_or_error,
+    parse_newline_data, Nut, ProtocolVersion, KEY_LENGTH, PROTOCOL_VERSIONS,
+};
+use crate::{error::SqrlError, Result, SecretString, SqrlUrl};
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
 };
-use crate::{error::SqrlError, Result};
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
-use std::{collections::HashMap, fmt, result, str::FromStr};
+use core::{fmt, result, str::FromStr};
+use url::{Host, Url};
 
 // The keys used to encode a server response
 const PROTOCOL_VERSION_KEY: &str = "ver";
 const NUT_KEY: &str = "nut";
-const TIF_KEY: &str = "tif";
-const QUERY_URL_KEY: &str = "qry";
+pub(crate) const TIF_KEY: &str = "tif";
+pub(crate) const QUERY_URL_KEY: &str = "qry";
 const SUCCESS_URL_KEY: &str = "url";
 const CANCEL_URL_KEY: &str = "can";
 const SECRET_INDEX_KEY: &str = "sin";
 const SERVER_UNLOCK_KEY_KEY: &str = "suk";
 const ASK_KEY: &str = "ask";
 
+// The most buttons a server can offer in an ask
+const MAX_ASK_BUTTONS: usize = 2;
+
+const KNOWN_KEYS: [&str; 9] = [
+    PROTOCOL_VERSION_KEY,
+    NUT_KEY,
+    TIF_KEY,
+    QUERY_URL_KEY,
+    SUCCESS_URL_KEY,
+    CANCEL_URL_KEY,
+    SECRET_INDEX_KEY,
+    SERVER_UNLOCK_KEY_KEY,
+    ASK_KEY,
+];
+
 /// An object representing a response from the server
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ServerResponse {
     /// The SQRL protocol versions supported by the server (ver)
     pub protocol_version: ProtocolVersion,
     /// The nut to be used for signing the next request (nut)
-    pub nut: String,
+    pub nut: Nut,
     /// A collection of transaction indication flags (tif)
     pub transaction_indication_flags: Vec<TIFValue>,
+    /// Any tif bits not understood by this crate, preserved so they are
+    /// included when the response is re-encoded
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unknown_bits: u16,
     /// The server object to query in the next request (qry)
-    pub query_url: String,
+    ///
+    /// The spec requires every response to include it, even one ending the
+    /// exchange such as the response to a remove, so a response without a
+    /// qry is rejected when parsed
+    pub query_url: QueryUrl,
     /// If CPS set, the url to redirect the client's browser to after
     /// successful authentication (url)
     pub success_url: Option<String>,
@@ -42,71 +76,426 @@ pub struct ServerResponse {
     /// A way for the server to request that the client display a prompt to the
     /// client user and return the selection (ask)
     pub ask: Option<String>,
+    // The base64 value exactly as received, with this crate's encoding of the
+    // response at that time, so an unmodified response can be echoed byte
+    // for byte. Both include any server unlock key (suk), so they are wiped
+    // like the other secrets.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    received: Option<Box<(SecretString, SecretString)>>,
+}
+
+impl PartialEq for ServerResponse {
+    fn eq(&self, other: &Self) -> bool {
+        self.protocol_version == other.protocol_version
+            && self.nut == other.nut
+            && self.transaction_indication_flags == other.transaction_indication_flags
+            && self.unknown_bits == other.unknown_bits
+            && self.query_url == other.query_url
+            && self.success_url == other.success_url
+            && self.cancel_url == other.cancel_url
+            && self.secret_index == other.secret_index
+            && self.server_unlock_key == other.server_unlock_key
+            && self.ask == other.ask
+    }
 }
 
 impl ServerResponse {
     /// Create a new server response object from the nut and tif values
     pub fn new(
-        nut: String,
+        nut: Nut,
         transaction_indication_flags: Vec<TIFValue>,
-        query_url: String,
+        query_url: QueryUrl,
     ) -> ServerResponse {
         ServerResponse {
             protocol_version: ProtocolVersion::new(PROTOCOL_VERSIONS).unwrap(),
             nut,
             transaction_indication_flags,
+            unknown_bits: 0,
             query_url,
             success_url: None,
             cancel_url: None,
             secret_index: None,
             server_unlock_key: None,
             ask: None,
+            received: None,
         }
     }
 
+    /// Create a new server response that advertises only the highest version
+    /// supported by both the server and the client, rather than the server's
+    /// full range
+    /// ```rust
+    /// use sqrl_protocol::{
+    ///     server_response::{QueryUrl, ServerResponse},
+    ///     Nut, ProtocolVersion,
+    /// };
+    ///
+    /// let response = ServerResponse::with_matching_version(
+    ///     Nut::parse("1WM9lfF1ST-z").unwrap(),
+    ///     vec![],
+    ///     QueryUrl::parse("/cli.sqrl?nut=1WM9lfF1ST-z").unwrap(),
+    ///     &ProtocolVersion::new("1").unwrap(),
+    ///     &ProtocolVersion::new("1").unwrap(),
+    /// )
+    /// .unwrap();
+    /// assert_eq!("1", response.protocol_version.to_string());
+    /// ```
+    pub fn with_matching_version(
+        nut: Nut,
+        transaction_indication_flags: Vec<TIFValue>,
+        query_url: QueryUrl,
+        server_version: &ProtocolVersion,
+        client_version: &ProtocolVersion,
+    ) -> Result<ServerResponse> {
+        let version = server_version.get_max_matching_version(client_version)?;
+        let mut response = ServerResponse::new(nut, transaction_indication_flags, query_url);
+        response.protocol_version = ProtocolVersion::new(&version.to_string())?;
+        Ok(response)
+    }
+
+    /// Create a response reporting that the client's command failed. The
+    /// CommandFailed flag is always set, along with any additional flags
+    /// describing the failure
+    /// ```rust
+    /// use sqrl_protocol::{
+    ///     server_response::{QueryUrl, ServerResponse, TIFValue},
+    ///     Nut,
+    /// };
+    ///
+    /// let nut = Nut::parse("1WM9lfF1ST-z").unwrap();
+    /// let qry = QueryUrl::parse("/cli.sqrl").unwrap();
+    /// let response = ServerResponse::error(nut, qry, &[TIFValue::BadId]);
+    /// assert_eq!(response.tif_u16(), 0x140);
+    /// ```
+    pub fn error(nut: Nut, query_url: QueryUrl, flags: &[TIFValue]) -> ServerResponse {
+        let mut transaction_indication_flags = vec![TIFValue::CommandFailed];
+        for flag in flags {
+            if !transaction_indication_flags.contains(flag) {
+                transaction_indication_flags.push(*flag);
+            }
+        }
+        ServerResponse::new(nut, transaction_indication_flags, query_url)
+    }
+
+    /// Create a response reporting that the client's command failed
+    pub fn command_failed(nut: Nut, query_url: QueryUrl) -> ServerResponse {
+        Self::error(nut, query_url, &[])
+    }
+
+    /// Create a response reporting that the client's request was invalid,
+    /// such as a bad signature or malformed parameters
+    pub fn client_failure(nut: Nut, query_url: QueryUrl) -> ServerResponse {
+        Self::error(nut, query_url, &[TIFValue::ClientFailure])
+    }
+
+    /// Create a response reporting that the identities in the request do not
+    /// match those the server knows
+    pub fn bad_id(nut: Nut, query_url: QueryUrl) -> ServerResponse {
+        Self::error(nut, query_url, &[TIFValue::ClientFailure, TIFValue::BadId])
+    }
+
+    /// Create a response reporting that the server does not support the
+    /// requested command
+    pub fn function_not_supported(nut: Nut, query_url: QueryUrl) -> ServerResponse {
+        Self::error(nut, query_url, &[TIFValue::FunctionNotSupported])
+    }
+
+    /// Create a response reporting that the server experienced a transient
+    /// error and the request should be retried
+    pub fn transient_error(nut: Nut, query_url: QueryUrl) -> ServerResponse {
+        Self::error(nut, query_url, &[TIFValue::TransientError])
+    }
+
     /// Decode a server response from a base64-encoded value
+    ///
+    /// The received value is kept, so [`ServerResponse::to_client_server_data`]
+    /// can echo it exactly.
     pub fn from_base64(base64_string: &str) -> Result<Self> {
         // Decode the response
-        let server_data = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(base64_string)?)?;
-        Self::from_str(&server_data)
+        let server_data = decode_base64_payload(base64_string)?;
+        let mut response = Self::from_str(&server_data)?;
+        response.received = Some(Box::new((
+            SecretString::from(base64_string),
+            SecretString::new(response.to_string()),
+        )));
+        Ok(response)
+    }
+
+    /// Parse a decoded server response, rejecting any keys this crate
+    /// doesn't recognize
+    ///
+    /// The lenient `from_str` ignores unknown keys so responses from newer
+    /// servers still parse. This is meant for conformance testing, where a
+    /// typo'd or unexpected key should be an error.
+    pub fn from_str_strict(s: &str) -> Result<Self> {
+        let data = parse_newline_data(s, "server response")?;
+        let unknown_keys: Vec<&str> = data
+            .keys()
+            .map(|key| key.as_str())
+            .filter(|key| !KNOWN_KEYS.contains(key))
+            .collect();
+        if !unknown_keys.is_empty() {
+            return Err(SqrlError::new(format!(
+                "Unknown keys in server response: {}",
+                unknown_keys.join(", ")
+            )));
+        }
+
+        Self::from_str(s)
+    }
+
+    /// Decode a server response from its raw (not base64-encoded) bytes, for
+    /// transports that pass the response as-is
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match core::str::from_utf8(bytes) {
+            Ok(server_data) => Self::from_str(server_data),
+            Err(e) => Err(SqrlError::new(format!(
+                "Server response is not valid utf-8: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Return the raw (not base64-encoded) bytes of the server response
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
     }
 
     /// Return the base64-encoded value of the server response
     pub fn to_base64(&self) -> String {
         BASE64_URL_SAFE_NO_PAD.encode(self.to_string().as_bytes())
     }
+
+    /// Return the base64 value a client should echo in the `server` field of
+    /// its next request
+    ///
+    /// A response parsed with [`ServerResponse::from_base64`] is echoed
+    /// exactly as received, whatever line endings, field order, or unknown
+    /// keys the server used. Once any field is changed, or for a response
+    /// built locally, the response is encoded with [`ServerResponse::to_base64`].
+    pub fn to_client_server_data(&self) -> String {
+        match self.received.as_deref() {
+            Some((received, encoded)) if encoded.as_str() == self.to_string() => {
+                received.to_string()
+            }
+            _ => self.to_base64(),
+        }
+    }
+
+    /// Get the combined numeric tif value, including any unknown bits
+    /// preserved from parsing
+    pub fn tif_u16(&self) -> u16 {
+        self.tif_flags().bits()
+    }
+
+    /// Get the tif values as a set of flags, including any unknown bits
+    /// preserved from parsing
+    pub fn tif_flags(&self) -> TifFlags {
+        TifFlags::from(self.transaction_indication_flags.as_slice())
+            .union(TifFlags(self.unknown_bits))
+    }
+
+    /// Resolve the query url (qry) against the SQRL url used to start the
+    /// authentication, returning the url for the next client request
+    ///
+    /// The query url must stay on the same host and port as the base url
+    pub fn resolve_query_url(&self, base: &SqrlUrl) -> Result<Url> {
+        let base = base.as_url();
+        let resolved = base.join(self.query_url.as_str())?;
+        if resolved.scheme() != base.scheme()
+            || resolved.host_str() != base.host_str()
+            || resolved.port() != base.port()
+        {
+            return Err(SqrlError::new(format!(
+                "Query url (qry) {} does not match the host of {}",
+                self.query_url, base
+            )));
+        }
+
+        Ok(resolved)
+    }
+
+    /// Parse the CPS success url (url) the client should send the user's
+    /// browser to, returning `None` if the server didn't send one
+    pub fn parsed_success_url(&self) -> Result<Option<Url>> {
+        Self::parse_cps_url(&self.success_url, "success url (url)")
+    }
+
+    /// Parse the CPS cancel url (can), returning `None` if the server didn't
+    /// send one
+    pub fn parsed_cancel_url(&self) -> Result<Option<Url>> {
+        Self::parse_cps_url(&self.cancel_url, "cancel url (can)")
+    }
+
+    /// Parse the question the server asked the client to show the user (ask),
+    /// returning `None` if the server didn't ask one
+    pub fn parsed_ask(&self) -> Result<Option<Ask>> {
+        self.ask.as_deref().map(Ask::parse).transpose()
+    }
+
+    /// Ask the client to show the user a question, encoding it as the ask
+    /// value of the response
+    /// ```rust
+    /// use sqrl_protocol::{
+    ///     server_response::{Ask, QueryUrl, ServerResponse},
+    ///     Nut,
+    /// };
+    ///
+    /// let ask = Ask::new("Continue?").button("Yes", None).unwrap();
+    /// let response = ServerResponse::new(
+    ///     Nut::parse("1WM9lfF1ST-z").unwrap(),
+    ///     vec![],
+    ///     QueryUrl::parse("/cli.sqrl?nut=1WM9lfF1ST-z").unwrap(),
+    /// )
+    /// .with_ask(&ask);
+    /// assert_eq!(Some(ask), response.parsed_ask().unwrap());
+    /// ```
+    pub fn with_ask(mut self, ask: &Ask) -> Self {
+        self.ask = Some(ask.to_wire());
+        self
+    }
+
+    fn parse_cps_url(url: &Option<String>, name: &str) -> Result<Option<Url>> {
+        match url {
+            Some(url) => match Url::parse(url) {
+                Ok(parsed) => Ok(Some(parsed)),
+                Err(e) => Err(SqrlError::new(format!(
+                    "Invalid {} in server response '{}': {}",
+                    name, url, e
+                ))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Determine what the server knows about the client's identities, based
+    /// on the IdentitySuperseded, CurrentIdMatch, PreviousIdMatch, and
+    /// SqrlDisabled flags. A superseded identity takes precedence over the
+    /// other flags.
+    pub fn identity_state(&self) -> IdentityState {
+        let flags = &self.transaction_indication_flags;
+        let current = flags.contains(&TIFValue::CurrentIdMatch);
+        let previous = flags.contains(&TIFValue::PreviousIdMatch);
+
+        if self.identity_superseded() {
+            IdentityState::Superseded
+        } else if !current && !previous {
+            IdentityState::Unknown
+        } else if flags.contains(&TIFValue::SqrlDisabled) {
+            IdentityState::Disabled
+        } else if current {
+            IdentityState::Known
+        } else {
+            IdentityState::KnownViaPrevious
+        }
+    }
+
+    /// Whether the server reported that the client's identity has been
+    /// superseded by a newer one. The client must abort the exchange and not
+    /// retry with this identity, since the server will never accept it again.
+    pub fn identity_superseded(&self) -> bool {
+        self.transaction_indication_flags
+            .contains(&TIFValue::IdentitySuperseded)
+    }
+
+    /// Whether the server asked the client to return an indexed secret (sin)
+    pub fn requested_index_secret(&self) -> bool {
+        self.secret_index.is_some()
+    }
+
+    /// The requested secret index as a number, if the server sent a numeric
+    /// sin. An index of 0 means the secret for the current site key, and
+    /// higher values chain back through previous keys. Returns `None` if no
+    /// secret was requested or the sin is not numeric, in which case it
+    /// should be treated as an opaque string.
+    pub fn secret_index_value(&self) -> Option<u32> {
+        self.secret_index.as_ref()?.parse::<u32>().ok()
+    }
+
+    /// Validate that the values in the server response are consistent
+    ///
+    /// `cps_requested` indicates whether the client asked for a
+    /// Client-Provided Session (opt=cps). The success url (url) and the
+    /// cancel url (can) are only allowed when CPS was requested, so a
+    /// response containing either one without CPS is rejected.
+    pub fn validate(&self, cps_requested: bool) -> Result<()> {
+        if !cps_requested {
+            if self.success_url.is_some() {
+                return Err(SqrlError::new(
+                    "Success url (url) set, but client did not request CPS".to_owned(),
+                ));
+            }
+            if self.cancel_url.is_some() {
+                return Err(SqrlError::new(
+                    "Cancel url (can) set, but client did not request CPS".to_owned(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate the server response as [`ServerResponse::validate`] does,
+    /// also requiring the CPS success url (url) and cancel url (can) to be
+    /// https, or to point at the local machine
+    ///
+    /// A client follows these urls on the user's behalf once it has signed
+    /// in, so a plain http url to another host would let anyone on the
+    /// network read or redirect the session. Loopback addresses are allowed
+    /// because CPS is commonly served from the local machine.
+    pub fn validate_strict(&self, cps_requested: bool) -> Result<()> {
+        self.validate(cps_requested)?;
+        Self::check_secure_cps_url(self.parsed_success_url()?, "Success url (url)")?;
+        Self::check_secure_cps_url(self.parsed_cancel_url()?, "Cancel url (can)")
+    }
+
+    fn check_secure_cps_url(url: Option<Url>, name: &str) -> Result<()> {
+        let url = match url {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+        let loopback = match url.host() {
+            Some(Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+            Some(Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        };
+        if url.scheme() != "https" && !loopback {
+            return Err(SqrlError::new(format!(
+                "{} must be https or localhost: {}",
+                name, url
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 impl fmt::Display for ServerResponse {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut map = HashMap::<&str, &str>::new();
+        let mut map = Vec::<(&str, &str)>::new();
         let protocol = self.protocol_version.to_string();
-        map.insert(PROTOCOL_VERSION_KEY, &protocol);
-        map.insert(NUT_KEY, &self.nut);
-
-        let mut tif: u16 = 0;
-        for t in &self.transaction_indication_flags {
-            tif |= *t as u16;
-        }
+        map.push((PROTOCOL_VERSION_KEY, &protocol));
+        map.push((NUT_KEY, self.nut.as_str()));
 
-        let tif_string = tif.to_string();
-        map.insert(TIF_KEY, &tif_string);
-        map.insert(QUERY_URL_KEY, &self.query_url);
+        let tif_string = self.tif_u16().to_string();
+        map.push((TIF_KEY, &tif_string));
+        map.push((QUERY_URL_KEY, self.query_url.as_str()));
 
         if let Some(url) = &self.success_url {
-            map.insert(SUCCESS_URL_KEY, url);
+            map.push((SUCCESS_URL_KEY, url));
         }
         if let Some(can) = &self.cancel_url {
-            map.insert(CANCEL_URL_KEY, can);
+            map.push((CANCEL_URL_KEY, can));
         }
         if let Some(sin) = &self.secret_index {
-            map.insert(SECRET_INDEX_KEY, sin);
+            map.push((SECRET_INDEX_KEY, sin));
         }
         if let Some(suk) = &self.server_unlock_key {
-            map.insert(SERVER_UNLOCK_KEY_KEY, suk);
+            map.push((SERVER_UNLOCK_KEY_KEY, suk));
         }
         if let Some(ask) = &self.ask {
-            map.insert(ASK_KEY, ask);
+            map.push((ASK_KEY, ask));
         }
 
         write!(f, "{}", &encode_newline_data(&map))
@@ -117,7 +506,7 @@ impl FromStr for ServerResponse {
     type Err = SqrlError;
 
     fn from_str(s: &str) -> result::Result<Self, Self::Err> {
-        let data = parse_newline_data(s)?;
+        let data = parse_newline_data(s, "server response")?;
 
         // Validate the protocol version is supported
         let ver_string = get_or_error(
@@ -126,39 +515,140 @@ impl FromStr for ServerResponse {
             "No version number in server response",
         )?;
         let protocol_version = ProtocolVersion::new(&ver_string)?;
-        let nut = get_or_error(&data, NUT_KEY, "No nut in server response")?;
+        let nut = Nut::parse(&get_or_error(&data, NUT_KEY, "No nut in server response")?)?;
         let tif_string = get_or_error(&data, TIF_KEY, "No status code (tif) in server response")?;
-        let transaction_indication_flags = TIFValue::parse_str(&tif_string)?;
+        let tif = TifFlags::from_u16(TIFValue::parse_decimal(&tif_string)?);
+        let transaction_indication_flags = tif.to_values();
+        // Preserve any unknown bits so they can be re-encoded
+        let unknown_bits = tif.unknown_bits();
 
-        let query_url = get_or_error(
+        let query_url = QueryUrl::parse(&get_or_error(
             &data,
             QUERY_URL_KEY,
             "No query url (qry) in server response",
-        )?;
+        )?)?;
 
         // The rest of these are optional
         let success_url = data.get(SUCCESS_URL_KEY).map(|x| x.to_string());
         let cancel_url = data.get(CANCEL_URL_KEY).map(|x| x.to_string());
         let secret_index = data.get(SECRET_INDEX_KEY).map(|x| x.to_string());
-        let server_unlock_key = data.get(SERVER_UNLOCK_KEY_KEY).map(|x| x.to_string());
+        let server_unlock_key = get_base64_field(&data, SERVER_UNLOCK_KEY_KEY, KEY_LENGTH)?;
         let ask = data.get(ASK_KEY).map(|x| x.to_string());
 
         Ok(ServerResponse {
             protocol_version,
             nut,
             transaction_indication_flags,
+            unknown_bits,
             query_url,
             success_url,
             cancel_url,
             secret_index,
             server_unlock_key,
             ask,
+            received: None,
+        })
+    }
+}
+
+/// The url the client should send its next request to (qry), either relative
+/// to the SQRL url or absolute
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryUrl {
+    url: String,
+}
+
+impl QueryUrl {
+    /// Parse a query url, verifying it is a valid relative or absolute url
+    /// ```rust
+    /// use sqrl_protocol::server_response::QueryUrl;
+    ///
+    /// let qry = QueryUrl::parse("/cli.sqrl?nut=1WM9lfF1ST-z").unwrap();
+    /// assert!(QueryUrl::parse("/cli.sqrl?nut=not a nut").is_err());
+    /// ```
+    pub fn parse(url: &str) -> Result<Self> {
+        // The url parser would quietly percent-encode these, so reject them here
+        if url.is_empty() || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(SqrlError::new(format!(
+                "Invalid query url (qry): '{}'",
+                url
+            )));
+        }
+
+        match Url::parse(url) {
+            Ok(_) => (),
+            Err(url::ParseError::RelativeUrlWithoutBase) => {
+                // Check the relative reference resolves against a placeholder base
+                Url::parse("sqrl://localhost/")?.join(url)?;
+            }
+            Err(e) => {
+                return Err(SqrlError::new(format!(
+                    "Invalid query url (qry) '{}': {}",
+                    url, e
+                )))
+            }
+        }
+
+        Ok(QueryUrl {
+            url: url.to_owned(),
         })
     }
+
+    /// Get the string value of the query url
+    pub fn as_str(&self) -> &str {
+        &self.url
+    }
+}
+
+impl fmt::Display for QueryUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+impl From<&SqrlUrl> for QueryUrl {
+    fn from(url: &SqrlUrl) -> Self {
+        QueryUrl {
+            url: url.query_path(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for QueryUrl {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.url)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for QueryUrl {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        let url = <String as serde::Deserialize>::deserialize(deserializer)?;
+        QueryUrl::parse(&url).map_err(serde::de::Error::custom)
+    }
+}
+
+/// What the server knows about the client's identities
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentityState {
+    /// The server knows the client's current identity (idk)
+    Known,
+    /// The server only knows the client's previous identity (pidk), so the
+    /// client should update the server to its current identity
+    KnownViaPrevious,
+    /// The server knows the client's identity, but SQRL is disabled for it
+    Disabled,
+    /// The server doesn't know either of the client's identities
+    Unknown,
+    /// The client's identity has been superseded and must no longer be used,
+    /// so the client should abort rather than retry
+    Superseded,
 }
 
 /// Transaction information flags
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TIFValue {
     /// A response indicating the current identity (idk) matches the known
     /// server identity
@@ -187,16 +677,83 @@ pub enum TIFValue {
     IdentitySuperseded = 0x200,
 }
 
+fn tif_error(value: &str) -> SqrlError {
+    SqrlError::new(format!(
+        "Unable to parse server response status code (tif): {}",
+        value
+    ))
+}
+
+impl fmt::Display for TIFValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", *self as u16)
+    }
+}
+
+impl FromStr for TIFValue {
+    type Err = SqrlError;
+
+    /// Parse a single flag from its decimal value, as sent on the wire. Use
+    /// [`TIFValue::parse_str`] for a combined tif value.
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let value = Self::parse_decimal(s)?;
+        match Self::from_u16(value).as_slice() {
+            [flag] if *flag as u16 == value => Ok(*flag),
+            _ => Err(SqrlError::new(format!("Invalid single tif value: {}", s))),
+        }
+    }
+}
+
 impl TIFValue {
-    /// Parse the TIF values based on a string
+    // All of the bits with a known TIFValue
+    const KNOWN_BITS: u16 = 0x3FF;
+
+    /// Parse the TIF values from their decimal value, as sent on the wire
     pub fn parse_str(value: &str) -> Result<Vec<Self>> {
-        match value.parse::<u16>() {
-            Ok(x) => Ok(Self::from_u16(x)),
-            Err(_) => Err(SqrlError::new(format!(
-                "Unable to parse server response status code (tif): {}",
-                value
-            ))),
+        Ok(Self::from_u16(Self::parse_decimal(value)?))
+    }
+
+    /// Parse the TIF values from a value copied out of logs or other tools,
+    /// which may also be hex with an explicit `0x` prefix. Only use this for
+    /// diagnostics; server responses are always decimal.
+    /// ```rust
+    /// use sqrl_protocol::server_response::TIFValue;
+    ///
+    /// assert_eq!(TIFValue::parse_str("5").unwrap(), TIFValue::parse_str_any("0x5").unwrap());
+    /// assert!(TIFValue::parse_str("0x5").is_err());
+    /// ```
+    pub fn parse_str_any(value: &str) -> Result<Vec<Self>> {
+        // Only an explicit prefix selects hex, so a value like "10" is never
+        // silently read in the wrong base
+        let parsed = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => u16::from_str_radix(hex, 16).map_err(|_| tif_error(value)),
+            None => Self::parse_decimal(value),
+        };
+
+        Ok(Self::from_u16(parsed?))
+    }
+
+    fn parse_decimal(value: &str) -> Result<u16> {
+        value.parse::<u16>().map_err(|_| tif_error(value))
+    }
+
+    /// Format the combined TIF values as a hex string (e.g. `0x5`), for
+    /// diagnostics
+    pub fn to_hex_string(values: &[Self]) -> String {
+        format!("{:#x}", Self::to_u16(values))
+    }
+
+    /// Combine the TIF values into a u16
+    pub fn to_u16(values: &[Self]) -> u16 {
+        let mut tif: u16 = 0;
+        for t in values {
+            tif |= *t as u16;
         }
+
+        tif
     }
 
     /// Parse the TIF values based on a u16
@@ -238,25 +795,370 @@ impl TIFValue {
     }
 }
 
+/// A question the server asks the client to show the user (ask), with up to
+/// two buttons the user can answer with
+///
+/// On the wire the message and each button are base64url encoded and joined
+/// with `~`, so any text can be sent. A button's label can be followed by
+/// `;` and a url for the client to open when it is pressed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ask {
+    message: String,
+    buttons: Vec<AskButton>,
+}
+
+/// A button the user can press to answer an [`Ask`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AskButton {
+    label: String,
+    url: Option<String>,
+}
+
+impl Ask {
+    /// Create a question with no buttons
+    pub fn new(message: &str) -> Self {
+        Ask {
+            message: message.to_owned(),
+            buttons: Vec::new(),
+        }
+    }
+
+    /// Add a button with an optional url to open when it is pressed. Fails
+    /// if the question already has two buttons, or if the label contains a
+    /// `;` (which separates the label from the url).
+    pub fn button(mut self, label: &str, url: Option<&str>) -> Result<Self> {
+        if self.buttons.len() >= MAX_ASK_BUTTONS {
+            return Err(SqrlError::new(format!(
+                "Invalid ask: At most {} buttons are allowed",
+                MAX_ASK_BUTTONS
+            )));
+        }
+        if label.contains(';') {
+            return Err(SqrlError::new(format!(
+                "Invalid ask: Button label can't contain ';': {}",
+                label
+            )));
+        }
+
+        self.buttons.push(AskButton {
+            label: label.to_owned(),
+            url: url.map(|x| x.to_owned()),
+        });
+        Ok(self)
+    }
+
+    /// Parse the ask value sent by a server
+    pub fn parse(ask: &str) -> Result<Self> {
+        let mut parts = ask.split('~');
+        let mut parsed = Ask::new(&Self::decode_part(parts.next().unwrap_or_default())?);
+        for part in parts {
+            let button = Self::decode_part(part)?;
+            parsed = match button.split_once(';') {
+                Some((label, url)) => parsed.button(label, Some(url))?,
+                None => parsed.button(&button, None)?,
+            };
+        }
+
+        Ok(parsed)
+    }
+
+    /// Encode the question as the ask value of a server response
+    pub fn to_wire(&self) -> String {
+        let mut parts = vec![BASE64_URL_SAFE_NO_PAD.encode(&self.message)];
+        for button in &self.buttons {
+            let text = match &button.url {
+                Some(url) => format!("{};{}", button.label, url),
+                None => button.label.clone(),
+            };
+            parts.push(BASE64_URL_SAFE_NO_PAD.encode(text));
+        }
+
+        parts.join("~")
+    }
+
+    /// Get the question to show the user
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Get the buttons the user can answer with, in order. Button 1 is the
+    /// first.
+    pub fn buttons(&self) -> &[AskButton] {
+        &self.buttons
+    }
+
+    fn decode_part(part: &str) -> Result<String> {
+        match decode_base64(part).map(String::from_utf8) {
+            Ok(Ok(text)) => Ok(text),
+            _ => Err(SqrlError::new(format!(
+                "Invalid ask: Unable to decode {}",
+                part
+            ))),
+        }
+    }
+}
+
+impl AskButton {
+    /// Get the text of the button
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Get the url the client should open if the button is pressed
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+}
+
+/// A set of transaction information flags stored as their combined `u16`
+/// value, for set operations that are awkward on a `Vec<TIFValue>`
+/// ```rust
+/// use sqrl_protocol::server_response::{TIFValue, TifFlags};
+///
+/// let mut flags = TifFlags::from_u16(0x5);
+/// assert!(flags.contains(TIFValue::IpsMatch));
+/// flags.insert(TIFValue::CommandFailed);
+/// flags.remove(TIFValue::IpsMatch);
+/// assert_eq!(0x41, flags.bits());
+/// assert_eq!(vec![TIFValue::CurrentIdMatch, TIFValue::CommandFailed], flags.to_values());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TifFlags(u16);
+
+impl TifFlags {
+    /// Create a set with no flags
+    pub fn empty() -> Self {
+        TifFlags(0)
+    }
+
+    /// Create a set from a combined tif value. Bits without a known
+    /// [`TIFValue`] are kept so they can be encoded again.
+    pub fn from_u16(bits: u16) -> Self {
+        TifFlags(bits)
+    }
+
+    /// Get the combined tif value
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    /// Get the bits that don't have a known [`TIFValue`]
+    pub fn unknown_bits(&self) -> u16 {
+        self.0 & !TIFValue::KNOWN_BITS
+    }
+
+    /// Whether the set has no flags
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether the flag is in the set
+    pub fn contains(&self, value: TIFValue) -> bool {
+        self.0 & value as u16 != 0
+    }
+
+    /// Add the flag to the set
+    pub fn insert(&mut self, value: TIFValue) {
+        self.0 |= value as u16;
+    }
+
+    /// Remove the flag from the set
+    pub fn remove(&mut self, value: TIFValue) {
+        self.0 &= !(value as u16);
+    }
+
+    /// Get the flags in either set
+    pub fn union(self, other: TifFlags) -> TifFlags {
+        TifFlags(self.0 | other.0)
+    }
+
+    /// Get the flags in both sets
+    pub fn intersection(self, other: TifFlags) -> TifFlags {
+        TifFlags(self.0 & other.0)
+    }
+
+    /// Get the known flags in the set, in ascending order
+    pub fn to_values(&self) -> Vec<TIFValue> {
+        TIFValue::from_u16(self.0)
+    }
+}
+
+impl From<TIFValue> for TifFlags {
+    fn from(value: TIFValue) -> Self {
+        TifFlags(value as u16)
+    }
+}
+
+impl From<&[TIFValue]> for TifFlags {
+    fn from(values: &[TIFValue]) -> Self {
+        TifFlags(TIFValue::to_u16(values))
+    }
+}
+
+impl From<TifFlags> for Vec<TIFValue> {
+    fn from(flags: TifFlags) -> Self {
+        flags.to_values()
+    }
+}
+
+impl FromIterator<TIFValue> for TifFlags {
+    fn from_iter<I: IntoIterator<Item = TIFValue>>(iter: I) -> Self {
+        let mut flags = TifFlags::empty();
+        for value in iter {
+            flags.insert(value);
+        }
+        flags
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{client_request::ServerData, MAX_PAYLOAD_LENGTH};
+    use proptest::{option, prelude::*};
     use rand::{distr::Alphanumeric, rng, Rng};
 
     const TEST_SERVER_RESPONSE: &str = "dmVyPTENCm51dD0xV005bGZGMVNULXoNCnRpZj01DQpxcnk9L2NsaS5zcXJsP251dD0xV005bGZGMVNULXoNCnN1az1CTUZEbTdiUGxzUW9qdUpzb0RUdmxTMU1jbndnU2N2a3RGODR2TGpzY0drDQo";
 
+    #[test]
+    fn server_response_with_matching_version() {
+        let nut = Nut::parse("1WM9lfF1ST-z").unwrap();
+        let qry = QueryUrl::parse("/cli.sqrl?nut=1WM9lfF1ST-z").unwrap();
+        let server = ProtocolVersion::new("1,5").unwrap();
+
+        let response = ServerResponse::with_matching_version(
+            nut.clone(),
+            vec![TIFValue::IpsMatch],
+            qry.clone(),
+            &server,
+            &ProtocolVersion::new("1-3").unwrap(),
+        )
+        .unwrap();
+        assert_eq!("1", response.protocol_version.to_string());
+        assert_eq!(
+            vec![TIFValue::IpsMatch],
+            response.transaction_indication_flags
+        );
+
+        let response = ServerResponse::with_matching_version(
+            nut,
+            vec![],
+            qry,
+            &server,
+            &ProtocolVersion::new("2-4").unwrap(),
+        );
+        assert!(response.is_err());
+    }
+
+    #[test]
+    fn server_response_requires_query_url() {
+        let response =
+            ServerResponse::from_str("ver=1\r\nnut=abc\r\ntif=5\r\nqry=/cli.sqrl\r\n").unwrap();
+        assert_eq!("/cli.sqrl", response.query_url.as_str());
+        assert!(response.to_string().contains("\r\nqry=/cli.sqrl\r\n"));
+
+        // Even a terminal response must tell the client where to go next
+        let data = "ver=1\r\nnut=abc\r\ntif=5\r\n";
+        match ServerResponse::from_str(data) {
+            Ok(_) => panic!("Parsed a response without a qry"),
+            Err(e) => assert_eq!("No query url (qry) in server response", e.to_string()),
+        }
+        assert!(ServerResponse::from_str(&format!("{}qry=\r\n", data)).is_err());
+    }
+
+    #[test]
+    fn server_response_reject_empty_nut() {
+        let data = "ver=1\r\nnut=\r\ntif=5\r\nqry=/cli.sqrl?nut=abc\r\n";
+        match ServerResponse::from_str(data) {
+            Ok(_) => panic!("Parsed a response with an empty nut"),
+            Err(e) => assert_eq!("Invalid nut, nut is empty", e.to_string()),
+        }
+        assert!(ServerResponse::from_base64(&BASE64_URL_SAFE_NO_PAD.encode(data)).is_err());
+    }
+
+    #[test]
+    fn server_response_validate_server_unlock_key() {
+        let data = "ver=1\r\nnut=abc\r\ntif=5\r\nqry=/cli.sqrl?nut=abc\r\n";
+        let suk = "BMFDm7bPlsQojuJsoDTvlS1McnwgScvktF84vLjscGk";
+        let response = ServerResponse::from_str(&format!("{}suk={}\r\n", data, suk)).unwrap();
+        assert_eq!(Some(suk), response.server_unlock_key.as_deref());
+
+        // Invalid base64 and the wrong number of bytes are both rejected
+        for suk in ["not+base64!", "BMFDm7bPlsQojuJsoDTvlS1Mcnw"] {
+            match ServerResponse::from_str(&format!("{}suk={}\r\n", data, suk)) {
+                Ok(_) => panic!("Parsed a malformed suk {}", suk),
+                Err(e) => assert_eq!(
+                    format!(
+                        "Invalid suk value, expected 32 base64url encoded bytes: {}",
+                        suk
+                    ),
+                    e.to_string()
+                ),
+            }
+        }
+    }
+
     #[test]
     fn server_response_validate_example() {
         let response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
         assert_eq!(response.protocol_version.to_string(), "1");
-        assert_eq!(response.nut, "1WM9lfF1ST-z");
-        assert_eq!(response.query_url, "/cli.sqrl?nut=1WM9lfF1ST-z");
+        assert_eq!(response.nut.as_str(), "1WM9lfF1ST-z");
+        assert_eq!(response.query_url.as_str(), "/cli.sqrl?nut=1WM9lfF1ST-z");
         assert_eq!(
             response.server_unlock_key.unwrap(),
             "BMFDm7bPlsQojuJsoDTvlS1McnwgScvktF84vLjscGk"
         )
     }
 
+    #[test]
+    fn server_response_requested_index_secret() {
+        let mut response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        assert!(!response.requested_index_secret());
+        assert_eq!(response.secret_index_value(), None);
+
+        response.secret_index = Some("0".to_owned());
+        let mut response = ServerResponse::from_base64(&response.to_base64()).unwrap();
+        assert!(response.requested_index_secret());
+        assert_eq!(response.secret_index_value(), Some(0));
+
+        response.secret_index = Some("login".to_owned());
+        assert!(response.requested_index_secret());
+        assert_eq!(response.secret_index_value(), None);
+    }
+
+    #[test]
+    fn server_response_error_constructors() {
+        let nut = Nut::parse("1WM9lfF1ST-z").unwrap();
+        let qry = QueryUrl::parse("/cli.sqrl").unwrap();
+
+        let response = ServerResponse::command_failed(nut.clone(), qry.clone());
+        assert_eq!(response.tif_u16(), 0x40);
+        assert_eq!(response.nut, nut);
+        assert_eq!(response.query_url, qry);
+
+        let response = ServerResponse::client_failure(nut.clone(), qry.clone());
+        assert_eq!(response.tif_u16(), 0xC0);
+
+        let response = ServerResponse::bad_id(nut.clone(), qry.clone());
+        assert_eq!(response.tif_u16(), 0x1C0);
+
+        let response = ServerResponse::function_not_supported(nut.clone(), qry.clone());
+        assert_eq!(response.tif_u16(), 0x50);
+
+        let response = ServerResponse::transient_error(nut.clone(), qry.clone());
+        assert_eq!(response.tif_u16(), 0x60);
+
+        let response =
+            ServerResponse::error(nut, qry, &[TIFValue::CommandFailed, TIFValue::IpsMatch]);
+        assert_eq!(
+            response.transaction_indication_flags,
+            vec![TIFValue::CommandFailed, TIFValue::IpsMatch]
+        );
+    }
+
     #[test]
     fn server_response_encode_decode() {
         let nut: String = rng()
@@ -264,11 +1166,13 @@ mod tests {
             .take(30)
             .map(char::from)
             .collect();
+        let nut = Nut::parse(&nut).unwrap();
         let qry: String = rng()
             .sample_iter(&Alphanumeric)
             .take(30)
             .map(char::from)
             .collect();
+        let qry = QueryUrl::parse(&qry).unwrap();
         let tif: u16 = rng().random_range(0..1023);
 
         let initial_response = ServerResponse::new(nut, TIFValue::from_u16(tif), qry);
@@ -277,6 +1181,364 @@ mod tests {
         assert_eq!(initial_response, decoded_response);
     }
 
+    #[test]
+    fn server_response_validate_cps_urls() {
+        let mut response = ServerResponse::new(
+            Nut::parse("nut").unwrap(),
+            vec![TIFValue::CurrentIdMatch],
+            QueryUrl::parse("/cli.sqrl?nut=nut").unwrap(),
+        );
+        response.validate(false).unwrap();
+        response.validate(true).unwrap();
+
+        response.success_url = Some("https://example.com/success".to_owned());
+        response.validate(true).unwrap();
+        assert!(response.validate(false).is_err());
+
+        response.success_url = None;
+        response.cancel_url = Some("https://example.com/cancel".to_owned());
+        response.validate(true).unwrap();
+        assert!(response.validate(false).is_err());
+    }
+
+    #[test]
+    fn server_response_validate_strict_cps_urls() {
+        let mut response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        response.validate_strict(true).unwrap();
+
+        response.success_url = Some("https://example.com/success".to_owned());
+        response.validate_strict(true).unwrap();
+        assert!(response.validate_strict(false).is_err());
+
+        for url in [
+            "http://localhost:25519/success",
+            "http://127.0.0.1:25519/success",
+            "http://[::1]:25519/success",
+        ] {
+            response.success_url = Some(url.to_owned());
+            response.validate_strict(true).unwrap();
+        }
+
+        // Plain http to another host is only allowed by the lenient check
+        response.success_url = Some("http://example.com/success".to_owned());
+        response.validate(true).unwrap();
+        match response.validate_strict(true) {
+            Ok(_) => panic!("Accepted an http success url"),
+            Err(e) => assert_eq!(
+                "Success url (url) must be https or localhost: http://example.com/success",
+                e.to_string()
+            ),
+        }
+
+        response.success_url = None;
+        response.cancel_url = Some("http://example.com/cancel".to_owned());
+        assert!(response.validate_strict(true).is_err());
+    }
+
+    #[test]
+    fn server_response_parsed_cps_urls() {
+        let mut response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        assert_eq!(None, response.parsed_success_url().unwrap());
+        assert_eq!(None, response.parsed_cancel_url().unwrap());
+
+        response.success_url = Some("https://example.com/success?token=abc".to_owned());
+        response.cancel_url = Some("https://example.com/cancel".to_owned());
+        assert_eq!(
+            "https://example.com/success?token=abc",
+            response.parsed_success_url().unwrap().unwrap().as_str()
+        );
+        assert_eq!(
+            Some("example.com"),
+            response.parsed_cancel_url().unwrap().unwrap().host_str()
+        );
+
+        response.success_url = Some("/relative/success".to_owned());
+        response.cancel_url = Some("https://[bad".to_owned());
+        assert!(response.parsed_success_url().is_err());
+        assert!(response.parsed_cancel_url().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn server_response_serde_json() {
+        let mut response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        response.ask = Some("Continue?~Yes~No".to_owned());
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains("\"protocol_version\":\"1\""));
+        let decoded: ServerResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(response, decoded);
+    }
+
+    #[test]
+    fn ask_round_trip() {
+        let ask = Ask::new("Transfer $5~ok; continue?")
+            .button("Yes", Some("https://example.com/yes?a=1;b=2"))
+            .unwrap()
+            .button("No~thanks", None)
+            .unwrap();
+        let wire = ask.to_wire();
+        assert_eq!(2, wire.matches('~').count());
+        assert!(!wire.contains(';'));
+
+        let parsed = Ask::parse(&wire).unwrap();
+        assert_eq!(ask, parsed);
+        assert_eq!("Transfer $5~ok; continue?", parsed.message());
+        assert_eq!("Yes", parsed.buttons()[0].label());
+        assert_eq!(
+            Some("https://example.com/yes?a=1;b=2"),
+            parsed.buttons()[0].url()
+        );
+        assert_eq!(None, parsed.buttons()[1].url());
+
+        assert_eq!(
+            ask,
+            Ask::parse(&Ask::parse(&wire).unwrap().to_wire()).unwrap()
+        );
+        assert_eq!(Ask::new(""), Ask::parse("").unwrap());
+    }
+
+    #[test]
+    fn ask_invalid() {
+        let ask = Ask::new("Continue?").button("A", None).unwrap();
+        assert!(ask.clone().button("B;C", None).is_err());
+        let ask = ask.button("B", None).unwrap();
+        assert!(ask.clone().button("C", None).is_err());
+
+        assert!(Ask::parse(&format!("{}~QQ", ask.to_wire())).is_err());
+        assert!(Ask::parse("not base64!").is_err());
+        assert!(Ask::parse("Q29udGludWU~_w").is_err());
+    }
+
+    #[test]
+    fn server_response_with_ask() {
+        let response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        assert_eq!(None, response.parsed_ask().unwrap());
+
+        let ask = Ask::new("Continue?").button("OK", None).unwrap();
+        let response = response.with_ask(&ask);
+        let decoded = ServerResponse::from_base64(&response.to_base64()).unwrap();
+        assert_eq!(Some(ask), decoded.parsed_ask().unwrap());
+    }
+
+    #[test]
+    fn server_response_preserve_unknown_tif_bits() {
+        let response =
+            ServerResponse::from_str("ver=1\nnut=1WM9lfF1ST-z\ntif=513\nqry=/cli.sqrl").unwrap();
+        assert_eq!(0, response.unknown_bits);
+        assert_eq!(513, response.tif_u16());
+
+        let response =
+            ServerResponse::from_str("ver=1\nnut=1WM9lfF1ST-z\ntif=5633\nqry=/cli.sqrl").unwrap();
+        assert_eq!(
+            vec![TIFValue::CurrentIdMatch, TIFValue::IdentitySuperseded],
+            response.transaction_indication_flags
+        );
+        assert_eq!(0x1400, response.unknown_bits);
+        assert_eq!(5633, response.tif_u16());
+        assert!(response.to_string().contains("\ntif=5633"));
+
+        let decoded = ServerResponse::from_base64(&response.to_base64()).unwrap();
+        assert_eq!(response, decoded);
+    }
+
+    #[test]
+    fn server_response_oversized_payload() {
+        let oversized = BASE64_URL_SAFE_NO_PAD.encode(format!(
+            "ver=1\nnut=1WM9lfF1ST-z\ntif=5\nqry=/cli.sqrl\nask={}",
+            "a".repeat(MAX_PAYLOAD_LENGTH)
+        ));
+        assert!(ServerResponse::from_base64(&oversized).is_err());
+    }
+
+    #[test]
+    fn query_url_parse() {
+        let qry = QueryUrl::parse("/cli.sqrl?nut=1WM9lfF1ST-z").unwrap();
+        assert_eq!("/cli.sqrl?nut=1WM9lfF1ST-z", qry.as_str());
+        QueryUrl::parse("sqrl://example.com/cli.sqrl?nut=x").unwrap();
+
+        assert!(QueryUrl::parse("").is_err());
+        assert!(QueryUrl::parse("/cli.sqrl?nut=a b").is_err());
+        assert!(QueryUrl::parse("/cli.sqrl\tnut").is_err());
+        assert!(QueryUrl::parse("https://exa mple.com/").is_err());
+    }
+
+    #[test]
+    fn query_url_from_sqrl_url() {
+        let sqrl_url = SqrlUrl::parse("sqrl://example.com/cli.sqrl?nut=abc").unwrap();
+        assert_eq!("/cli.sqrl?nut=abc", QueryUrl::from(&sqrl_url).as_str());
+    }
+
+    #[test]
+    fn server_response_reject_invalid_query_url() {
+        let data = "ver=1\r\nnut=abc\r\ntif=5\r\nqry=/cli.sqrl?nut=a b\r\n";
+        assert!(ServerResponse::from_str(data).is_err());
+        let data = "ver=1\r\nnut=abc\r\ntif=5\r\nqry=/cli.sqrl?nut=abc\r\n";
+        assert!(ServerResponse::from_str(data).is_ok());
+    }
+
+    #[test]
+    fn server_response_to_from_bytes() {
+        let response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        let bytes = response.to_bytes();
+        assert!(String::from_utf8(bytes.clone())
+            .unwrap()
+            .starts_with("ver=1\r\nnut=1WM9lfF1ST-z\r\n"));
+        assert_eq!(response, ServerResponse::from_bytes(&bytes).unwrap());
+        assert_eq!(BASE64_URL_SAFE_NO_PAD.encode(&bytes), response.to_base64());
+
+        assert!(ServerResponse::from_bytes(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn server_response_to_client_server_data() {
+        let response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        assert_eq!(TEST_SERVER_RESPONSE, response.to_client_server_data());
+
+        // LF line endings, a different field order, and an unknown key all
+        // re-encode differently, but must still be echoed as received
+        let received = BASE64_URL_SAFE_NO_PAD
+            .encode("tif=5\nqry=/cli.sqrl?nut=abc\nfoo=bar\nnut=abc\nver=1\n".as_bytes());
+        let mut response = ServerResponse::from_base64(&received).unwrap();
+        assert_ne!(received, response.to_base64());
+        assert_eq!(received, response.to_client_server_data());
+        assert_eq!(
+            received,
+            ServerData::from_response(response.clone()).to_base64()
+        );
+
+        // A changed response no longer matches what was received
+        response.transaction_indication_flags = vec![TIFValue::CurrentIdMatch];
+        assert_eq!(response.to_base64(), response.to_client_server_data());
+    }
+
+    #[test]
+    fn server_response_from_str_strict() {
+        let data = "ver=1\r\nnut=abc\r\ntif=5\r\nqry=/cli.sqrl?nut=abc\r\nfoo=bar\r\n";
+        let lenient = ServerResponse::from_str(data).unwrap();
+        match ServerResponse::from_str_strict(data) {
+            Ok(_) => panic!("Parsed a response with an unknown key"),
+            Err(e) => assert_eq!("Unknown keys in server response: foo", e.to_string()),
+        }
+
+        let data = "ver=1\r\nnut=abc\r\ntif=5\r\nqry=/cli.sqrl?nut=abc\r\n";
+        assert_eq!(lenient, ServerResponse::from_str_strict(data).unwrap());
+    }
+
+    #[test]
+    fn server_response_tif_u16() {
+        let response = ServerResponse::new(
+            Nut::parse("1WM9lfF1ST-z").unwrap(),
+            vec![TIFValue::CurrentIdMatch, TIFValue::IpsMatch],
+            QueryUrl::parse("/cli.sqrl?nut=1WM9lfF1ST-z").unwrap(),
+        );
+        assert_eq!(5, response.tif_u16());
+    }
+
+    #[test]
+    fn tif_value_parse_hex() {
+        let decimal = TIFValue::parse_str("5").unwrap();
+        assert_eq!(vec![TIFValue::CurrentIdMatch, TIFValue::IpsMatch], decimal);
+        assert_eq!(decimal, TIFValue::parse_str_any("5").unwrap());
+        assert_eq!(decimal, TIFValue::parse_str_any("0x5").unwrap());
+        assert_eq!(decimal, TIFValue::parse_str_any("0X5").unwrap());
+
+        // Hex is opt-in, the wire format is decimal
+        assert!(TIFValue::parse_str("0x5").is_err());
+
+        // "10" is decimal, not hex
+        assert_eq!(
+            vec![TIFValue::PreviousIdMatch, TIFValue::SqrlDisabled],
+            TIFValue::parse_str_any("10").unwrap()
+        );
+        assert_eq!(
+            vec![TIFValue::FunctionNotSupported],
+            TIFValue::parse_str_any("0x10").unwrap()
+        );
+
+        assert!(TIFValue::parse_str_any("0x").is_err());
+        assert!(TIFValue::parse_str_any("c4").is_err());
+        assert!(TIFValue::parse_str_any("0xfffff").is_err());
+    }
+
+    #[test]
+    fn server_response_rejects_hex_tif() {
+        let response = "ver=1\r\nnut=1WM9lfF1ST-z\r\ntif=0x5\r\nqry=/cli.sqrl?nut=1WM9lfF1ST-z\r\n";
+        assert!(ServerResponse::from_str(response).is_err());
+        assert!(ServerResponse::from_str(&response.replace("0x5", "5")).is_ok());
+    }
+
+    #[test]
+    fn tif_value_to_hex_string() {
+        assert_eq!("0x0", TIFValue::to_hex_string(&[]));
+        assert_eq!("0x1c4", TIFValue::to_hex_string(&TIFValue::from_u16(0x1C4)));
+    }
+
+    #[test]
+    fn tif_value_to_u16() {
+        assert_eq!(0, TIFValue::to_u16(&[]));
+        assert_eq!(674, TIFValue::to_u16(&TIFValue::from_u16(674)));
+        assert_eq!(
+            0x101,
+            TIFValue::to_u16(&[TIFValue::CurrentIdMatch, TIFValue::BadId])
+        );
+    }
+
+    #[test]
+    fn server_response_resolve_query_url() {
+        let mut response = ServerResponse::new(
+            Nut::parse("x").unwrap(),
+            vec![TIFValue::CurrentIdMatch],
+            QueryUrl::parse("/cli.sqrl?nut=x").unwrap(),
+        );
+        let base = SqrlUrl::parse("sqrl://example.com").unwrap();
+        assert_eq!(
+            "sqrl://example.com/cli.sqrl?nut=x",
+            response.resolve_query_url(&base).unwrap().as_str()
+        );
+
+        let base = SqrlUrl::parse("sqrl://example.com:8443/auth/login?nut=y").unwrap();
+        assert_eq!(
+            "sqrl://example.com:8443/cli.sqrl?nut=x",
+            response.resolve_query_url(&base).unwrap().as_str()
+        );
+
+        response.query_url = QueryUrl::parse("sqrl://evil.com/cli.sqrl?nut=x").unwrap();
+        assert!(response.resolve_query_url(&base).is_err());
+    }
+
+    #[test]
+    fn server_response_identity_state() {
+        let state = |tif: u16| {
+            ServerResponse::new(
+                Nut::parse("x").unwrap(),
+                TIFValue::from_u16(tif),
+                QueryUrl::parse("/cli.sqrl?nut=x").unwrap(),
+            )
+            .identity_state()
+        };
+
+        assert_eq!(IdentityState::Known, state(0x5));
+        assert_eq!(IdentityState::Known, state(0x3));
+        assert_eq!(IdentityState::KnownViaPrevious, state(0x6));
+        assert_eq!(IdentityState::Disabled, state(0xD));
+        assert_eq!(IdentityState::Disabled, state(0xA));
+        assert_eq!(IdentityState::Unknown, state(0x4));
+        assert_eq!(IdentityState::Unknown, state(0x8));
+        assert_eq!(IdentityState::Superseded, state(0x200));
+        assert_eq!(IdentityState::Superseded, state(0x205));
+    }
+
+    #[test]
+    fn server_response_identity_superseded() {
+        let mut response = ServerResponse::from_base64(TEST_SERVER_RESPONSE).unwrap();
+        assert!(!response.identity_superseded());
+
+        response.transaction_indication_flags = TIFValue::from_u16(0x200);
+        assert!(response.identity_superseded());
+        assert_eq!(IdentityState::Superseded, response.identity_state());
+    }
+
     #[test]
     fn tif_value_from_string() {
         let resp = TIFValue::parse_str("674").unwrap();
@@ -287,6 +1549,70 @@ mod tests {
         assert!(resp.contains(&TIFValue::IdentitySuperseded));
     }
 
+    #[test]
+    fn tif_value_from_str_display() {
+        for value in TIFValue::from_u16(TIFValue::KNOWN_BITS) {
+            assert_eq!(value, TIFValue::from_str(&value.to_string()).unwrap());
+        }
+        assert_eq!("64", TIFValue::CommandFailed.to_string());
+        assert_eq!(
+            TIFValue::IdentitySuperseded,
+            TIFValue::from_str("512").unwrap()
+        );
+        assert!(TIFValue::from_str("0x200").is_err());
+
+        // Combined, unknown, and garbage values aren't a single flag
+        assert!(TIFValue::from_str("5").is_err());
+        assert!(TIFValue::from_str("0").is_err());
+        assert!(TIFValue::from_str("0x400").is_err());
+        assert!(TIFValue::from_str("garbage").is_err());
+    }
+
+    #[test]
+    fn tif_flags_set_operations() {
+        let mut flags = TifFlags::empty();
+        assert!(flags.is_empty());
+        flags.insert(TIFValue::CurrentIdMatch);
+        flags.insert(TIFValue::IpsMatch);
+        flags.insert(TIFValue::IpsMatch);
+        assert_eq!(0x5, flags.bits());
+        assert!(flags.contains(TIFValue::IpsMatch));
+        assert!(!flags.contains(TIFValue::SqrlDisabled));
+
+        flags.remove(TIFValue::IpsMatch);
+        flags.remove(TIFValue::BadId);
+        assert_eq!(TifFlags::from(TIFValue::CurrentIdMatch), flags);
+
+        let failed: TifFlags = [TIFValue::CommandFailed, TIFValue::ClientFailure]
+            .into_iter()
+            .collect();
+        assert_eq!(0xC1, flags.union(failed).bits());
+        assert!(flags.intersection(failed).is_empty());
+        assert_eq!(failed, TifFlags::from_u16(0xC5).intersection(failed));
+    }
+
+    #[test]
+    fn tif_flags_round_trip() {
+        for bits in [0, 0x5, 0x1C0, TIFValue::KNOWN_BITS] {
+            let flags = TifFlags::from_u16(bits);
+            let values: Vec<TIFValue> = flags.into();
+            assert_eq!(TIFValue::from_u16(bits), values);
+            assert_eq!(flags, TifFlags::from(values.as_slice()));
+        }
+
+        // Unknown bits are kept in the flags, but have no value
+        let flags = TifFlags::from_u16(0x1201);
+        assert_eq!(0x1000, flags.unknown_bits());
+        assert_eq!(
+            vec![TIFValue::CurrentIdMatch, TIFValue::IdentitySuperseded],
+            flags.to_values()
+        );
+
+        let response =
+            ServerResponse::from_str("ver=1\nnut=1WM9lfF1ST-z\ntif=4613\nqry=/cli.sqrl").unwrap();
+        assert_eq!(TifFlags::from_u16(4613), response.tif_flags());
+    }
+
     #[test]
     fn tif_value_from_u16() {
         let resp = TIFValue::from_u16(73);
@@ -295,4 +1621,67 @@ mod tests {
         assert!(resp.contains(&TIFValue::SqrlDisabled));
         assert!(resp.contains(&TIFValue::CommandFailed));
     }
+
+    fn server_response_strategy(
+        optional_field: impl Strategy<Value = Option<String>> + Clone,
+    ) -> impl Strategy<Value = ServerResponse> {
+        (
+            prop::sample::select(vec!["1", "1-3", "1,3-5", "1-"]),
+            "[A-Za-z0-9_-]{1,30}",
+            0..=TIFValue::KNOWN_BITS,
+            (0..=u16::MAX).prop_map(|x| x & !TIFValue::KNOWN_BITS),
+            "/[A-Za-z0-9/._-]{0,20}(\\?nut=[A-Za-z0-9_-]{1,20})?",
+            (
+                optional_field.clone(),
+                optional_field.clone(),
+                optional_field.clone(),
+                // The suk must be a base64url encoded key, so only its presence
+                // follows the optional field
+                (optional_field.clone(), prop::array::uniform32(any::<u8>()))
+                    .prop_map(|(field, key)| field.map(|_| BASE64_URL_SAFE_NO_PAD.encode(key))),
+                optional_field,
+            ),
+        )
+            .prop_map(
+                |(ver, nut, tif, unknown_bits, qry, (url, can, sin, suk, ask))| ServerResponse {
+                    protocol_version: ProtocolVersion::new(ver).unwrap(),
+                    nut: Nut::parse(&nut).unwrap(),
+                    transaction_indication_flags: TIFValue::from_u16(tif),
+                    unknown_bits,
+                    query_url: QueryUrl::parse(&qry).unwrap(),
+                    success_url: url,
+                    cancel_url: can,
+                    secret_index: sin,
+                    server_unlock_key: suk,
+                    ask,
+                    received: None,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn server_response_round_trip(
+            response in server_response_strategy(option::of("[A-Za-z0-9~:/?&=._-]{1,40}"))
+        ) {
+            let decoded = ServerResponse::from_base64(&response.to_base64()).unwrap();
+            prop_assert_eq!(response, decoded);
+        }
+
+        #[test]
+        fn server_response_round_trip_all_optional_fields(
+            response in server_response_strategy("[A-Za-z0-9~:/?&=._-]{1,40}".prop_map(Some))
+        ) {
+            let decoded = ServerResponse::from_base64(&response.to_base64()).unwrap();
+            prop_assert_eq!(response, decoded);
+        }
+
+        #[test]
+        fn server_response_round_trip_no_optional_fields(
+            response in server_response_strategy(Just(None))
+        ) {
+            let decoded = ServerResponse::from_base64(&response.to_base64()).unwrap();
+            prop_assert_eq!(response, decoded);
+        }
+    }
 }
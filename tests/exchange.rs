@@ -0,0 +1,188 @@
+//! Run a full SQRL exchange between a client and an in-memory server
+#![cfg(feature = "getrandom")]
+
+use ed25519_dalek::SigningKey;
+use sqrl_protocol::{
+    client_request::{ClientCommand, ClientRequest, ClientRequestBuilder, ServerData},
+    encode_public_key,
+    error::SqrlError,
+    server_response::{IdentityState, QueryUrl, ServerResponse, TIFValue},
+    Nut, SqrlUrl,
+};
+use std::collections::HashMap;
+
+const SERVER_URL: &str = "sqrl://example.com/cli.sqrl";
+
+// A minimal server that tracks the outstanding nut and the identities it
+// knows, along with their unlock keys
+struct TestServer {
+    last_response: Option<ServerResponse>,
+    issued_nut: Nut,
+    identities: HashMap<String, (String, String)>,
+}
+
+impl TestServer {
+    fn new() -> Self {
+        TestServer {
+            last_response: None,
+            issued_nut: Nut::generate().unwrap(),
+            identities: HashMap::new(),
+        }
+    }
+
+    fn sqrl_url(&self) -> SqrlUrl {
+        SqrlUrl::parse(&format!("{}?nut={}", SERVER_URL, self.issued_nut)).unwrap()
+    }
+
+    fn handle(&mut self, body: &str) -> String {
+        let response = match self.process(body) {
+            Ok(response) => response,
+            Err(_) => ServerResponse::client_failure(self.next_nut(), self.query_url()),
+        };
+        self.last_response = Some(response.clone());
+        response.to_base64()
+    }
+
+    fn process(&mut self, body: &str) -> sqrl_protocol::Result<ServerResponse> {
+        let request = ClientRequest::from_form_body(body)?;
+        request.verify_and_validate()?;
+
+        // The request must echo the url or response the server last sent
+        let nut = match (&request.server_data, &self.last_response) {
+            (ServerData::Url { url }, None) => url.nut()?,
+            (ServerData::ServerResponse { .. }, Some(last))
+                if request.server_data.matches_response(last) =>
+            {
+                last.nut.clone()
+            }
+            _ => return Err(SqrlError::new("Bad server data".into())),
+        };
+        if nut != self.issued_nut {
+            return Err(SqrlError::new("Stale nut".into()));
+        }
+
+        let identity = encode_public_key(request.identity_key());
+        let known = self.identities.contains_key(&identity);
+        match request.command() {
+            ClientCommand::Query => (),
+            ClientCommand::Ident => {
+                if !known {
+                    let params = &request.client_params;
+                    self.identities.insert(
+                        identity,
+                        (
+                            params.server_unlock_key.clone().unwrap(),
+                            params.verify_unlock_key.clone().unwrap(),
+                        ),
+                    );
+                }
+            }
+            _ => {
+                return Ok(ServerResponse::function_not_supported(
+                    self.next_nut(),
+                    self.query_url(),
+                ))
+            }
+        }
+
+        let mut flags = vec![TIFValue::IpsMatch];
+        if known || *request.command() == ClientCommand::Ident {
+            flags.push(TIFValue::CurrentIdMatch);
+        }
+        Ok(ServerResponse::new(
+            self.next_nut(),
+            flags,
+            self.query_url(),
+        ))
+    }
+
+    fn next_nut(&mut self) -> Nut {
+        self.issued_nut = Nut::generate().unwrap();
+        self.issued_nut.clone()
+    }
+
+    fn query_url(&self) -> QueryUrl {
+        QueryUrl::parse(&format!("/cli.sqrl?nut={}", self.issued_nut)).unwrap()
+    }
+}
+
+// Stand-ins for the unlock keys a real client derives from its identity
+// unlock key
+fn unlock_keys() -> (String, String) {
+    let suk = encode_public_key(&SigningKey::from_bytes(&[8; 32]).verifying_key());
+    let vuk = encode_public_key(&SigningKey::from_bytes(&[9; 32]).verifying_key());
+    (suk, vuk)
+}
+
+#[test]
+fn query_then_ident() {
+    let mut server = TestServer::new();
+    let identity = SigningKey::from_bytes(&[1; 32]);
+    let url = server.sqrl_url();
+
+    // First round trip: the client queries with the advertised url
+    let query = ClientRequestBuilder::new(ClientCommand::Query, ServerData::from(url.clone()))
+        .build_and_sign(&identity)
+        .unwrap();
+    let response = server.handle(&format!("{}\n", query.to_query_string()));
+    let response = ServerResponse::from_base64(&response).unwrap();
+    assert_eq!(IdentityState::Unknown, response.identity_state());
+    assert_eq!(
+        format!("sqrl://example.com/cli.sqrl?nut={}", response.nut),
+        response.resolve_query_url(&url).unwrap().as_str()
+    );
+
+    // Second round trip: the client associates its identity with the server
+    let (suk, vuk) = unlock_keys();
+    let ident =
+        ClientRequestBuilder::new(ClientCommand::Ident, ServerData::from_response(response))
+            .server_unlock_key(suk.clone())
+            .verify_unlock_key(vuk.clone())
+            .build_and_sign(&identity)
+            .unwrap();
+    let response = ServerResponse::from_base64(&server.handle(&ident.to_query_string())).unwrap();
+    assert_eq!(IdentityState::Known, response.identity_state());
+    assert_eq!(
+        Some(&(suk, vuk)),
+        server
+            .identities
+            .get(&encode_public_key(&identity.verifying_key()))
+    );
+}
+
+#[test]
+fn tampered_request_is_rejected() {
+    let mut server = TestServer::new();
+    let identity = SigningKey::from_bytes(&[1; 32]);
+    let mut query =
+        ClientRequestBuilder::new(ClientCommand::Query, ServerData::from(server.sqrl_url()))
+            .build_and_sign(&identity)
+            .unwrap();
+
+    // Re-pointing the request at another identity invalidates the signature
+    query.client_params.identity_key = SigningKey::from_bytes(&[2; 32]).verifying_key();
+    let response = ServerResponse::from_base64(&server.handle(&query.to_query_string())).unwrap();
+    assert!(response
+        .transaction_indication_flags
+        .contains(&TIFValue::ClientFailure));
+    assert!(server.identities.is_empty());
+}
+
+#[test]
+fn replayed_nut_is_rejected() {
+    let mut server = TestServer::new();
+    let identity = SigningKey::from_bytes(&[1; 32]);
+    let query =
+        ClientRequestBuilder::new(ClientCommand::Query, ServerData::from(server.sqrl_url()))
+            .build_and_sign(&identity)
+            .unwrap();
+
+    let response = ServerResponse::from_base64(&server.handle(&query.to_query_string())).unwrap();
+    assert_eq!(IdentityState::Unknown, response.identity_state());
+
+    // Sending the first request again echoes a nut the server has moved past
+    let response = ServerResponse::from_base64(&server.handle(&query.to_query_string())).unwrap();
+    assert!(response
+        .transaction_indication_flags
+        .contains(&TIFValue::ClientFailure));
+}
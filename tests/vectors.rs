@@ -0,0 +1,91 @@
+//! Check the crate against the test vectors in `tests/vectors`
+//!
+//! Each `.txt` file holds one vector as `key=value` lines, with `#` starting
+//! a comment:
+//! - `kind`: what the input is: `client_request`, `client_parameters`,
+//!   `server_response`, or `server_data`
+//! - `input`: the value as sent on the wire
+//! - `expected`: the value the crate should encode after parsing the input,
+//!   or `error` if the input must be rejected
+
+use sqrl_protocol::{
+    client_request::{ClientParameters, ClientRequest, ServerData},
+    server_response::ServerResponse,
+    Result,
+};
+use std::{collections::HashMap, fs, path::PathBuf};
+
+struct Vector {
+    name: String,
+    kind: String,
+    input: String,
+    expected: String,
+}
+
+fn load_vectors() -> Vec<Vector> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/vectors");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let contents = fs::read_to_string(path).unwrap();
+            let mut fields: HashMap<&str, &str> = contents
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| {
+                    line.split_once('=')
+                        .unwrap_or_else(|| panic!("{}: malformed line '{}'", name, line))
+                })
+                .collect();
+            let mut field = |key: &str| {
+                fields
+                    .remove(key)
+                    .unwrap_or_else(|| panic!("{}: missing {}", name, key))
+                    .to_owned()
+            };
+
+            Vector {
+                kind: field("kind"),
+                input: field("input"),
+                expected: field("expected"),
+                name,
+            }
+        })
+        .collect()
+}
+
+// Parse the input as the vector's kind and encode it again
+fn round_trip(vector: &Vector) -> Result<String> {
+    match vector.kind.as_str() {
+        "client_request" => Ok(ClientRequest::from_query_string(&vector.input)?.to_query_string()),
+        "client_parameters" => Ok(ClientParameters::from_base64(&vector.input)?.to_base64()),
+        "server_response" => Ok(ServerResponse::from_base64(&vector.input)?.to_base64()),
+        "server_data" => Ok(ServerData::from_base64(&vector.input)?.to_base64()),
+        kind => panic!("{}: unknown kind {}", vector.name, kind),
+    }
+}
+
+#[test]
+fn vectors_round_trip() {
+    let vectors = load_vectors();
+    assert!(!vectors.is_empty());
+
+    for vector in vectors {
+        match (round_trip(&vector), vector.expected.as_str()) {
+            (Err(_), "error") => (),
+            (Ok(encoded), "error") => {
+                panic!("{}: expected an error, but got {}", vector.name, encoded)
+            }
+            (Err(e), _) => panic!("{}: failed to parse: {}", vector.name, e),
+            (Ok(encoded), expected) => assert_eq!(expected, encoded, "{}", vector.name),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Check that index secrets are wiped before their memory is freed
+#![cfg(feature = "zeroize")]
+
+use ed25519_dalek::SigningKey;
+use sqrl_protocol::{
+    client_request::{ClientCommand, ClientParameters, ClientRequest, ClientRequestBuilder},
+    SecretString, SqrlUrl,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// The index secret as sent (ins), and the 32 bytes it decodes to
+const SECRET: &str = "aW5kZXgtc2VjcmV0LXRoYXQtbXVzdC1ub3QtbGVhayE";
+const SECRET_BYTES: &[u8] = b"index-secret-that-must-not-leak!";
+
+// Counts the freed buffers that still hold the secret, in either form,
+// anywhere in the buffer
+struct CheckingAllocator;
+
+static LEAKED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CheckingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let buffer = slice::from_raw_parts(ptr, layout.size());
+        if [SECRET.as_bytes(), SECRET_BYTES]
+            .iter()
+            .any(|secret| buffer.windows(secret.len()).any(|x| x == *secret))
+        {
+            LEAKED.fetch_add(1, Ordering::SeqCst);
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CheckingAllocator = CheckingAllocator;
+
+#[test]
+fn index_secrets_wiped_on_drop() {
+    let identity = SigningKey::from_bytes(&[1; 32]);
+    let url = SqrlUrl::parse("sqrl://example.com/cli.sqrl?nut=1234abcd").unwrap();
+
+    // Check the allocator sees an unwiped secret being freed, even inside a
+    // larger buffer
+    drop([b"ins=", SECRET.as_bytes(), b"\r\n"].concat());
+    drop(SECRET_BYTES.to_vec());
+    assert_eq!(2, LEAKED.swap(0, Ordering::SeqCst));
+
+    // A builder that is never used
+    let builder = ClientRequestBuilder::new(ClientCommand::Query, url.clone().into())
+        .index_secret(SECRET.to_owned())
+        .previous_index_secret(SECRET.to_owned());
+    drop(builder);
+    assert_eq!(0, LEAKED.load(Ordering::SeqCst));
+
+    // Build, encode, parse and verify a request
+    let request = ClientRequestBuilder::new(ClientCommand::Query, url.into())
+        .index_secret(SECRET.to_owned())
+        .previous_index_secret(SECRET.to_owned())
+        .build_and_sign(&identity)
+        .unwrap();
+    let query_string = request.to_query_string();
+    let parsed = ClientRequest::from_query_string(&query_string).unwrap();
+    parsed.verify_and_validate().unwrap();
+    assert_eq!(Some(SECRET), parsed.client_params.index_secret.as_deref());
+    assert_eq!(query_string, parsed.to_query_string());
+    drop(parsed);
+    drop(request);
+    assert_eq!(0, LEAKED.load(Ordering::SeqCst));
+
+    // Parameters set directly, including clones and a changed parsed copy
+    let mut params = ClientParameters::new(ClientCommand::Query, identity.verifying_key());
+    params.index_secret = Some(SecretString::from(SECRET));
+    params.previous_index_secret = Some(SecretString::from(SECRET));
+    let mut decoded = ClientParameters::from_base64(&params.to_base64()).unwrap();
+    decoded.button = Some(1);
+    drop(decoded);
+
+    // The parameters have no Drop impl of their own, so they can still be
+    // destructured and used in struct update syntax with the feature enabled
+    let ClientParameters { index_secret, .. } = ClientParameters {
+        button: Some(2),
+        ..params.clone()
+    };
+    drop(index_secret);
+    drop(params.clone());
+    drop(params);
+    assert_eq!(0, LEAKED.load(Ordering::SeqCst));
+}